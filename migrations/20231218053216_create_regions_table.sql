@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS regions (
+  id INT PRIMARY KEY,
+  name VARCHAR(50)
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS chat_messages (
+  id CHAR(26) PRIMARY KEY,
+  room_id BIGINT NOT NULL,
+  user_name TEXT NOT NULL,
+  message TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS chat_messages_room_id_id_idx ON chat_messages (room_id, id);
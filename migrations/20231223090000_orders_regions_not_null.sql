@@ -0,0 +1,5 @@
+ALTER TABLE orders
+  ALTER COLUMN region_id SET NOT NULL,
+  ALTER COLUMN gift_name SET NOT NULL,
+  ALTER COLUMN quantity SET NOT NULL;
+ALTER TABLE regions ALTER COLUMN name SET NOT NULL;
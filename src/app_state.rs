@@ -1,39 +1,70 @@
-use std::sync::{atomic::AtomicU64, Arc};
+use std::sync::Arc;
 
-use shuttle_persist::PersistInstance;
-use shuttle_secrets::SecretStore;
-use tokio::sync::broadcast;
+use axum::extract::FromRef;
 
-#[derive(Clone)]
+use crate::{
+    archive::ArchiveLimits,
+    auth::Auth,
+    chatroom::{moderation::Moderation, views::ViewCounts, ChatConfig, ChatRooms},
+    config::Secrets,
+    game::{GameConfig, Games, Leaderboard},
+    persist::PersistStore,
+    repo::{DynChatRepository, DynOrderRepository, DynRegionRepository, PgRepository},
+    ws::{WsConfig, WsShutdown},
+};
+
+#[derive(Clone, FromRef)]
 pub struct AppState {
-    pub secrect_store: Arc<SecretStore>,
-    pub persist: Arc<PersistInstance>,
+    pub secrets: Arc<Secrets>,
+    pub auth: Auth,
+    pub persist: Arc<dyn PersistStore>,
     pub db: sqlx::PgPool,
-    pub chatroom_broadcaster: broadcast::Sender<ChatroomMessage>,
-    pub chatroom_counter: Arc<AtomicU64>,
-}
-
-#[derive(Debug, Clone, serde::Serialize)]
-pub struct ChatroomMessageBody {
-    pub user: String,
-    pub message: String,
-}
-
-#[derive(Debug, Clone)]
-pub struct ChatroomMessage {
-    pub room: u64,
-    pub body: ChatroomMessageBody,
+    pub orders: DynOrderRepository,
+    pub regions: DynRegionRepository,
+    pub chat: DynChatRepository,
+    pub chatrooms: Arc<ChatRooms>,
+    pub chat_config: Arc<ChatConfig>,
+    pub moderation: Arc<Moderation>,
+    pub views: Arc<ViewCounts>,
+    pub games: Arc<Games>,
+    pub archive_limits: Arc<ArchiveLimits>,
+    pub ws_config: Arc<WsConfig>,
+    pub ws_shutdown: WsShutdown,
 }
 
 impl AppState {
-    pub fn new(secrect_store: SecretStore, persist: PersistInstance, db: sqlx::PgPool) -> Self {
-        let (chatroom_broadcaster, _) = broadcast::channel(1024);
+    /// Must be called from within a Tokio runtime.
+    pub fn new(
+        secrets: impl Into<Secrets>,
+        persist: impl PersistStore + 'static,
+        db: sqlx::PgPool,
+    ) -> Self {
+        let secrets = secrets.into();
+        let repository = PgRepository::new(db.clone());
+        let chat_config = ChatConfig::from_secrets(&secrets);
+        let persist: Arc<dyn PersistStore> = Arc::new(persist);
+        let views = Arc::new(ViewCounts::load(persist.clone()));
+        views.spawn_flusher();
+        let games = Games::new(
+            GameConfig::from_secrets(&secrets),
+            Arc::new(Leaderboard::load(persist.clone())),
+        );
         Self {
-            secrect_store: Arc::new(secrect_store),
-            persist: Arc::new(persist),
+            auth: Auth::from_secrets(&secrets, db.clone()),
+            moderation: Arc::new(Moderation::from_secrets(&secrets)),
+            ws_config: Arc::new(WsConfig::from_secrets(&secrets)),
+            archive_limits: Arc::new(ArchiveLimits::from_secrets(&secrets)),
+            secrets: Arc::new(secrets),
+            persist,
             db,
-            chatroom_broadcaster,
-            chatroom_counter: Arc::new(AtomicU64::new(0)),
+            orders: Arc::new(repository.clone()),
+            regions: Arc::new(repository.clone()),
+            chat: Arc::new(repository),
+            chatrooms: Arc::new(ChatRooms::new(&chat_config)),
+            chat_config: Arc::new(chat_config),
+            views,
+            games: Arc::new(games),
+            ws_shutdown: WsShutdown::new(),
         }
     }
 }
@@ -0,0 +1,338 @@
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+};
+
+use tar::{EntryType, Header};
+
+use super::ArchiveLimits;
+use crate::prelude::*;
+
+/// Formats accepted by the archive endpoints.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGzip,
+    TarZstd,
+    Zip,
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+/// A local file header, or the end of an empty archive.
+const ZIP_MAGICS: [&[u8]; 2] = [b"PK\x03\x04", b"PK\x05\x06"];
+/// `ustar` in both the POSIX and the GNU headers.
+const TAR_MAGIC: &[u8] = b"ustar";
+const TAR_MAGIC_OFFSET: usize = 257;
+/// Bytes needed to recognize any of the formats.
+const HEAD_LEN: usize = TAR_MAGIC_OFFSET + TAR_MAGIC.len();
+
+impl Format {
+    /// Recognize the format from the first bytes of the body, then from its
+    /// `Content-Type`; an empty body is an empty tar archive.
+    pub fn detect(head: &[u8], content_type: Option<&str>) -> Result<Self> {
+        if head.is_empty() {
+            return Ok(Self::Tar);
+        }
+        if let Some(format) = Self::from_magic(head) {
+            return Ok(format);
+        }
+        content_type
+            .and_then(Self::from_content_type)
+            .ok_or_else(|| {
+                AppError::UnsupportedMediaType(
+                    "unrecognized archive format, expected tar, tar.gz, tar.zst or zip".to_string(),
+                )
+            })
+    }
+
+    fn from_magic(body: &[u8]) -> Option<Self> {
+        if body.starts_with(GZIP_MAGIC) {
+            Some(Self::TarGzip)
+        } else if body.starts_with(ZSTD_MAGIC) {
+            Some(Self::TarZstd)
+        } else if ZIP_MAGICS.iter().any(|magic| body.starts_with(magic)) {
+            Some(Self::Zip)
+        } else if body[TAR_MAGIC_OFFSET.min(body.len())..].starts_with(TAR_MAGIC) {
+            Some(Self::Tar)
+        } else {
+            None
+        }
+    }
+
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        let essence = content_type.split(';').next().unwrap_or("").trim();
+        match essence.to_ascii_lowercase().as_str() {
+            "application/x-tar" | "application/tar" => Some(Self::Tar),
+            "application/gzip" | "application/x-gzip" | "application/x-gtar" => Some(Self::TarGzip),
+            "application/zstd" | "application/x-zstd" => Some(Self::TarZstd),
+            "application/zip" | "application/x-zip-compressed" => Some(Self::Zip),
+            _ => None,
+        }
+    }
+}
+
+/// Detect the format of an uploaded archive and read it as an uncompressed tar.
+///
+/// Compressed tars are decompressed as they are read. Zip archives, whose
+/// index is at the end, are first written to a temporary file, then converted
+/// to a tar in another one within the limits.
+pub fn open<R>(
+    mut body: R,
+    content_type: Option<&str>,
+    limits: &ArchiveLimits,
+) -> Result<Box<dyn Read + Send>>
+where
+    R: Read + Send + 'static,
+{
+    let mut head = Vec::with_capacity(HEAD_LEN);
+    (&mut body)
+        .take(HEAD_LEN as u64)
+        .read_to_end(&mut head)
+        .map_err(super::invalid)?;
+    let format = Format::detect(&head, content_type)?;
+    tracing::trace!("Reading {:?} archive", format);
+
+    let reader = Cursor::new(head).chain(body);
+    Ok(match format {
+        Format::Tar => Box::new(reader),
+        Format::TarGzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Format::TarZstd => Box::new(zstd::Decoder::new(reader).map_err(super::invalid)?),
+        Format::Zip => {
+            let mut file = tempfile::tempfile()?;
+            io::copy(&mut { reader }, &mut file).map_err(super::invalid)?;
+            file.rewind()?;
+            Box::new(zip_to_tar(file, limits)?)
+        }
+    })
+}
+
+/// Unix file type bits of a symlink.
+const S_IFLNK: u32 = 0o120000;
+const S_IFMT: u32 = 0o170000;
+
+/// Rewrite a zip archive as a tar in a temporary file, keeping unsafe paths
+/// for the inspection to report them.
+fn zip_to_tar<R: Read + Seek>(reader: R, limits: &ArchiveLimits) -> Result<File> {
+    let mut zip = zip::ZipArchive::new(reader)
+        .map_err(|e| AppError::BadRequest(f!("invalid zip archive: {e}")))?;
+    if zip.len() > limits.max_entries {
+        return Err(AppError::PayloadTooLarge(f!(
+            "archive has more than {} entries",
+            limits.max_entries
+        )));
+    }
+
+    let mut builder = tar::Builder::new(tempfile::tempfile()?);
+    let mut remaining = limits.max_unpacked_bytes;
+    let too_large = || {
+        AppError::PayloadTooLarge(f!(
+            "archive unpacks to more than {} bytes",
+            limits.max_unpacked_bytes
+        ))
+    };
+    for i in 0..zip.len() {
+        let mut file = zip
+            .by_index(i)
+            .map_err(|e| AppError::BadRequest(f!("invalid zip entry: {e}")))?;
+        let mode = file.unix_mode();
+        let is_symlink = mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK);
+        let mut header = Header::new_gnu();
+        header.set_mode(mode.unwrap_or(if file.is_dir() { 0o755 } else { 0o644 }) & 0o7777);
+        header.set_mtime(zip_mtime(file.last_modified()));
+        header.set_size(0);
+        let name = file.name().to_string();
+
+        if is_symlink {
+            let mut target = Vec::new();
+            (&mut file)
+                .take(remaining.saturating_add(1))
+                .read_to_end(&mut target)
+                .map_err(super::invalid)?;
+            remaining = remaining
+                .checked_sub(target.len() as u64)
+                .ok_or_else(too_large)?;
+            header.set_entry_type(EntryType::Symlink);
+            append_long(&mut builder, EntryType::GNULongLink, &target)?;
+            set_raw(
+                &mut header.as_gnu_mut().expect("gnu header").linkname,
+                &target,
+            );
+        } else if file.is_dir() {
+            header.set_entry_type(EntryType::Directory);
+        } else {
+            header.set_entry_type(EntryType::Regular);
+        }
+        append_long(&mut builder, EntryType::GNULongName, name.as_bytes())?;
+        set_raw(
+            &mut header.as_gnu_mut().expect("gnu header").name,
+            name.as_bytes(),
+        );
+
+        if header.entry_type() == EntryType::Regular {
+            // the declared size may lie, only trust what is actually decompressed
+            let size = append_streamed(builder.get_mut(), header, &mut file, remaining)?;
+            remaining = remaining.checked_sub(size).ok_or_else(too_large)?;
+        } else {
+            header.set_cksum();
+            builder.append(&header, io::empty())?;
+        }
+    }
+
+    let mut tar = builder.into_inner()?;
+    tar.rewind()?;
+    Ok(tar)
+}
+
+const BLOCK_SIZE: usize = 512;
+
+/// Decompress an entry straight into the tar, then write its header once its
+/// size is known. Stops after one byte more than `limit`, which the caller
+/// refuses.
+fn append_streamed(
+    tar: &mut File,
+    mut header: Header,
+    data: &mut impl Read,
+    limit: u64,
+) -> Result<u64> {
+    let start = tar.stream_position()?;
+    tar.write_all(&[0; BLOCK_SIZE])?;
+    let size = io::copy(&mut data.take(limit.saturating_add(1)), tar).map_err(super::invalid)?;
+    let padding = (BLOCK_SIZE - (size % BLOCK_SIZE as u64) as usize) % BLOCK_SIZE;
+    tar.write_all(&[0; BLOCK_SIZE][..padding])?;
+
+    header.set_size(size);
+    header.set_cksum();
+    tar.seek(SeekFrom::Start(start))?;
+    tar.write_all(header.as_bytes())?;
+    tar.seek(SeekFrom::End(0))?;
+    Ok(size)
+}
+
+/// Copy a path into a header field as is, without the checks of `set_path`.
+fn set_raw(field: &mut [u8], path: &[u8]) {
+    let len = path.len().min(field.len());
+    field[..len].copy_from_slice(&path[..len]);
+}
+
+/// Write the GNU extension entry for a path that does not fit in the header.
+fn append_long<W: Write>(
+    builder: &mut tar::Builder<W>,
+    kind: EntryType,
+    path: &[u8],
+) -> io::Result<()> {
+    if path.len() < 100 {
+        return Ok(());
+    }
+    let mut header = Header::new_gnu();
+    set_raw(
+        &mut header.as_gnu_mut().expect("gnu header").name,
+        b"././@LongLink",
+    );
+    header.set_entry_type(kind);
+    header.set_mode(0o644);
+    header.set_size(path.len() as u64 + 1);
+    header.set_cksum();
+    builder.append(&header, [path, b"\0"].concat().as_slice())
+}
+
+/// Seconds since the Unix epoch of a zip timestamp, which has no time zone.
+fn zip_mtime(time: zip::DateTime) -> u64 {
+    chrono::NaiveDate::from_ymd_opt(time.year().into(), time.month().into(), time.day().into())
+        .and_then(|date| {
+            date.and_hms_opt(
+                time.hour().into(),
+                time.minute().into(),
+                time.second().into(),
+            )
+        })
+        .map_or(0, |time| time.and_utc().timestamp().max(0) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::archive::inspect;
+
+    fn tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "cookie/santa.txt", &b"COOKIE"[..5])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn zip() -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default().unix_permissions(0o600);
+        zip.add_directory("cookie/", options).unwrap();
+        zip.start_file("cookie/santa.txt", options).unwrap();
+        zip.write_all(b"COOKI").unwrap();
+        zip.add_symlink("cookie/up", "../..", options).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[rstest]
+    #[case::tar(tar(), None, 1)]
+    #[case::gzip({
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(&tar()).unwrap();
+        gz.finish().unwrap()
+    }, None, 1)]
+    #[case::zstd(zstd::encode_all(&tar()[..], 0).unwrap(), Some("application/zstd"), 1)]
+    #[case::zip(zip(), None, 3)]
+    fn test_open_any_format(
+        #[case] body: Vec<u8>,
+        #[case] content_type: Option<&str>,
+        #[case] entries: usize,
+    ) {
+        let limits = ArchiveLimits::default();
+        let reader = open(Cursor::new(body), content_type, &limits).unwrap();
+        let manifest = inspect(reader, &limits).unwrap();
+        assert_eq!(manifest.entries.len(), entries);
+        assert_eq!(manifest.total_size, 5);
+    }
+
+    #[rstest]
+    #[case(10, true)]
+    #[case(u64::MAX, true)]
+    #[case(9, false)]
+    fn test_zip_unpacked_size_limit(#[case] max_unpacked_bytes: u64, #[case] accepted: bool) {
+        let limits = ArchiveLimits {
+            max_unpacked_bytes,
+            ..Default::default()
+        };
+        // the symlink target counts too
+        let result =
+            open(Cursor::new(zip()), None, &limits).and_then(|reader| inspect(reader, &limits));
+        assert_eq!(result.is_ok(), accepted);
+    }
+
+    #[test]
+    fn test_zip_keeps_unsafe_entries() {
+        let limits = ArchiveLimits::default();
+        let reader = open(Cursor::new(zip()), None, &limits).unwrap();
+        let manifest = inspect(reader, &limits).unwrap();
+        let up = &manifest.entries[2];
+        assert_eq!(up.link_target.as_deref(), Some("../.."));
+        assert!(up.problem.is_some());
+        assert_eq!(manifest.entries[1].mode, "0600");
+    }
+
+    #[rstest]
+    #[case(b"hello world", None)]
+    #[case(b"hello world", Some("application/x-www-form-urlencoded"))]
+    fn test_unsupported_format(#[case] body: &[u8], #[case] content_type: Option<&str>) {
+        assert!(matches!(
+            Format::detect(body, content_type),
+            Err(AppError::UnsupportedMediaType(_))
+        ));
+    }
+}
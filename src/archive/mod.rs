@@ -0,0 +1,416 @@
+use std::{
+    collections::HashSet,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+
+use serde::Serialize;
+use tar::{Archive, Entry, EntryType};
+
+use crate::{config::Secrets, prelude::*};
+
+mod format;
+mod upload;
+
+pub use format::{open, Format};
+pub use upload::{blocking_reader, limit_upload};
+
+/// How much an uploaded archive may unpack to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveLimits {
+    /// Sum of the sizes of the entries, in bytes.
+    pub max_unpacked_bytes: u64,
+    pub max_entries: usize,
+    /// Most directories an entry may be nested in.
+    pub max_depth: usize,
+    /// Largest request body of the streaming endpoints, in bytes.
+    pub max_upload_bytes: u64,
+}
+
+impl Default for ArchiveLimits {
+    fn default() -> Self {
+        Self {
+            max_unpacked_bytes: 100 * 1024 * 1024,
+            max_entries: 10_000,
+            max_depth: 32,
+            max_upload_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl ArchiveLimits {
+    /// Read from the `ARCHIVE_MAX_UNPACKED_BYTES`, `ARCHIVE_MAX_ENTRIES`,
+    /// `ARCHIVE_MAX_DEPTH` and `ARCHIVE_MAX_UPLOAD_BYTES` secrets.
+    pub fn from_secrets(secrets: &Secrets) -> Self {
+        let default = Self::default();
+        Self {
+            max_unpacked_bytes: secrets
+                .parse("ARCHIVE_MAX_UNPACKED_BYTES")
+                .unwrap_or(default.max_unpacked_bytes),
+            max_entries: secrets
+                .parse("ARCHIVE_MAX_ENTRIES")
+                .unwrap_or(default.max_entries),
+            max_depth: secrets
+                .parse("ARCHIVE_MAX_DEPTH")
+                .unwrap_or(default.max_depth),
+            max_upload_bytes: secrets
+                .parse("ARCHIVE_MAX_UPLOAD_BYTES")
+                .unwrap_or(default.max_upload_bytes),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Hardlink,
+    /// Devices, fifos and other special files, never unpacked.
+    Other,
+}
+
+impl From<EntryType> for EntryKind {
+    fn from(kind: EntryType) -> Self {
+        match kind {
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => Self::File,
+            EntryType::Directory => Self::Directory,
+            EntryType::Symlink => Self::Symlink,
+            EntryType::Link => Self::Hardlink,
+            _ => Self::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    /// Permissions in octal, e.g. `0644`.
+    pub mode: String,
+    /// Last modification, in seconds since the Unix epoch.
+    pub mtime: u64,
+    #[serde(rename = "type")]
+    pub kind: EntryKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    /// Why unpacking the entry would be unsafe.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+    pub total_size: u64,
+    /// Whether every entry can be unpacked without escaping the destination.
+    pub safe: bool,
+}
+
+/// Checks the entries of an archive, in order, before they are unpacked.
+///
+/// Paths are resolved lexically, which is only sound because entries may not
+/// go through a symlink of the archive nor replace a directory with one.
+#[derive(Debug)]
+struct Inspector<'a> {
+    limits: &'a ArchiveLimits,
+    entries: usize,
+    total_size: u64,
+    /// Paths of the symlinks seen so far.
+    symlinks: HashSet<PathBuf>,
+    /// Directories containing the entries seen so far.
+    directories: HashSet<PathBuf>,
+}
+
+impl<'a> Inspector<'a> {
+    fn new(limits: &'a ArchiveLimits) -> Self {
+        Self {
+            limits,
+            entries: 0,
+            total_size: 0,
+            symlinks: HashSet::new(),
+            directories: HashSet::new(),
+        }
+    }
+
+    /// Describe the entry; fails if the archive goes over the limits.
+    fn inspect<R: Read>(&mut self, entry: &Entry<'_, R>) -> Result<ManifestEntry> {
+        let header = entry.header();
+        let path = entry.path().map_err(invalid)?.into_owned();
+        let kind = EntryKind::from(header.entry_type());
+        let size = header.size().map_err(invalid)?;
+
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            return Err(AppError::PayloadTooLarge(f!(
+                "archive has more than {} entries",
+                self.limits.max_entries
+            )));
+        }
+        self.total_size += size;
+        if self.total_size > self.limits.max_unpacked_bytes {
+            return Err(AppError::PayloadTooLarge(f!(
+                "archive unpacks to more than {} bytes",
+                self.limits.max_unpacked_bytes
+            )));
+        }
+
+        let link_target = entry
+            .link_name()
+            .map_err(invalid)?
+            .map(|target| target.to_string_lossy().into_owned());
+        let problem = self.check(&path, kind, link_target.as_deref()).err();
+        Ok(ManifestEntry {
+            path: path.to_string_lossy().into_owned(),
+            size,
+            mode: f!("{:04o}", header.mode().map_err(invalid)? & 0o7777),
+            mtime: header.mtime().map_err(invalid)?,
+            kind,
+            link_target,
+            problem,
+        })
+    }
+
+    fn check(
+        &mut self,
+        path: &Path,
+        kind: EntryKind,
+        link_target: Option<&str>,
+    ) -> std::result::Result<(), String> {
+        let path = normalize(path)?;
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let depth = path.components().count() - 1;
+        if depth > self.limits.max_depth {
+            return Err(f!(
+                "nested in {depth} directories, at most {} are allowed",
+                self.limits.max_depth
+            ));
+        }
+        self.check_not_through_symlink(&path)?;
+        if self.symlinks.contains(&path) {
+            return Err("replaces a symlink".to_string());
+        }
+
+        match (kind, link_target) {
+            (EntryKind::Other, _) => return Err("special files are not unpacked".to_string()),
+            (EntryKind::Symlink, Some(target)) => {
+                if self.directories.contains(&path) {
+                    return Err("replaces a directory with a symlink".to_string());
+                }
+                let parent = path.parent().unwrap_or(Path::new(""));
+                let target = resolve_symlink(parent, Path::new(target))?;
+                self.check_not_through_symlink(&target)?;
+                self.symlinks.insert(path.clone());
+            }
+            (EntryKind::Hardlink, Some(target)) => {
+                let target = normalize(Path::new(target))?;
+                self.check_not_through_symlink(&target)?;
+            }
+            (EntryKind::Symlink | EntryKind::Hardlink, None) => {
+                return Err("link without a target".to_string());
+            }
+            _ => {}
+        }
+
+        let dirs = match kind {
+            EntryKind::Directory => path.ancestors(),
+            _ => path.parent().unwrap_or(Path::new("")).ancestors(),
+        };
+        self.directories.extend(dirs.map(Path::to_path_buf));
+        Ok(())
+    }
+
+    fn check_not_through_symlink(&self, path: &Path) -> std::result::Result<(), String> {
+        match path
+            .ancestors()
+            .skip(1)
+            .find(|dir| self.symlinks.contains(*dir))
+        {
+            Some(link) => Err(f!("goes through the symlink {}", link.display())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An archive that could not be read, most likely corrupted.
+pub fn invalid(e: io::Error) -> AppError {
+    if upload::is_over_limit(&e) {
+        return AppError::PayloadTooLarge("upload is over the size limit".to_string());
+    }
+    AppError::BadRequest(f!("invalid archive: {e}"))
+}
+
+/// The path without `.`, refusing absolute paths and `..`.
+fn normalize(path: &Path) -> std::result::Result<PathBuf, String> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => return Err("path contains `..`".to_string()),
+            Component::RootDir | Component::Prefix(_) => return Err("path is absolute".to_string()),
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Where a symlink in `parent` points to, relative to the root of the archive.
+///
+/// `..` is only allowed at the start of the target, so that it never follows
+/// another symlink of the archive.
+fn resolve_symlink(parent: &Path, target: &Path) -> std::result::Result<PathBuf, String> {
+    let mut resolved = parent.to_path_buf();
+    let mut leading = true;
+    for component in target.components() {
+        match component {
+            Component::Normal(part) => {
+                leading = false;
+                resolved.push(part);
+            }
+            Component::CurDir => {}
+            Component::ParentDir if leading => {
+                if !resolved.pop() {
+                    return Err("symlink points outside of the archive".to_string());
+                }
+            }
+            Component::ParentDir => {
+                return Err("symlink target has `..` after a directory".to_string())
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err("symlink target is absolute".to_string())
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// List the entries of a tar archive and whether they are safe to unpack.
+pub fn inspect<R: Read>(reader: R, limits: &ArchiveLimits) -> Result<Manifest> {
+    let mut archive = Archive::new(reader);
+    let mut inspector = Inspector::new(limits);
+    let mut entries = Vec::new();
+    for entry in archive.entries().map_err(invalid)? {
+        entries.push(inspector.inspect(&entry.map_err(invalid)?)?);
+    }
+
+    Ok(Manifest {
+        safe: entries.iter().all(|e| e.problem.is_none()),
+        total_size: inspector.total_size,
+        entries,
+    })
+}
+
+/// Unpack a tar archive into `dest`, checking every entry before unpacking it.
+///
+/// Stops at the first unsafe entry, leaving the entries before it unpacked.
+pub fn unpack<R: Read>(reader: R, dest: &Path, limits: &ArchiveLimits) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    let mut archive = Archive::new(reader);
+    let mut inspector = Inspector::new(limits);
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let manifest = inspector.inspect(&entry)?;
+        if let Some(problem) = manifest.problem {
+            return Err(AppError::UnprocessableEntity(f!(
+                "unsafe archive entry {}: {problem}",
+                manifest.path
+            )));
+        }
+        entry.unpack_in(dest)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn entry(builder: &mut tar::Builder<Vec<u8>>, path: &str, kind: EntryType, target: &str) {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(0o644);
+        header.set_mtime(1_703_462_400);
+        header.set_size(0);
+        // set the raw names so that unsafe paths are not refused by the builder
+        let gnu = header.as_gnu_mut().unwrap();
+        gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+        gnu.linkname[..target.len()].copy_from_slice(target.as_bytes());
+        header.set_cksum();
+        builder.append(&header, std::io::empty()).unwrap();
+    }
+
+    fn archive(entries: &[(&str, EntryType, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, kind, target) in entries {
+            entry(&mut builder, path, *kind, target);
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[rstest]
+    #[case::plain(&[("a/b.txt", EntryType::Regular, ""), ("a/c", EntryType::Symlink, "b.txt")], true)]
+    #[case::up_inside(&[("a/b/c", EntryType::Symlink, "../../d")], true)]
+    #[case::traversal(&[("../evil", EntryType::Regular, "")], false)]
+    #[case::absolute(&[("/etc/passwd", EntryType::Regular, "")], false)]
+    #[case::symlink_up(&[("a/c", EntryType::Symlink, "../../etc")], false)]
+    #[case::symlink_absolute(&[("c", EntryType::Symlink, "/etc")], false)]
+    #[case::through_symlink(&[("c", EntryType::Symlink, "."), ("c/passwd", EntryType::Regular, "")], false)]
+    #[case::dir_to_symlink(&[("a", EntryType::Directory, ""), ("a", EntryType::Symlink, "b")], false)]
+    #[case::chained_up(&[("c", EntryType::Symlink, "."), ("d", EntryType::Symlink, "c/..")], false)]
+    #[case::hardlink_up(&[("h", EntryType::Link, "../etc/passwd")], false)]
+    #[case::fifo(&[("f", EntryType::Fifo, "")], false)]
+    fn test_inspect_detects_escapes(
+        #[case] entries: &[(&str, EntryType, &str)],
+        #[case] safe: bool,
+    ) {
+        let manifest = inspect(&archive(entries)[..], &ArchiveLimits::default()).unwrap();
+        assert_eq!(manifest.safe, safe, "{manifest:?}");
+        assert_eq!(manifest.entries.len(), entries.len());
+    }
+
+    #[test]
+    fn test_limits() {
+        let tar = archive(&[
+            ("a/b/c/d", EntryType::Regular, ""),
+            ("e", EntryType::Regular, ""),
+        ]);
+        let limits = ArchiveLimits {
+            max_depth: 2,
+            ..Default::default()
+        };
+        let manifest = inspect(&tar[..], &limits).unwrap();
+        assert!(manifest.entries[0].problem.is_some());
+        assert_eq!(manifest.entries[1].mode, "0644");
+
+        let limits = ArchiveLimits {
+            max_entries: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            inspect(&tar[..], &limits),
+            Err(AppError::PayloadTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_unpack_stops_at_unsafe_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar = archive(&[
+            ("ok.txt", EntryType::Regular, ""),
+            ("up", EntryType::Symlink, "../.."),
+            ("later.txt", EntryType::Regular, ""),
+        ]);
+        let result = unpack(&tar[..], dir.path(), &ArchiveLimits::default());
+        assert!(matches!(result, Err(AppError::UnprocessableEntity(_))));
+        assert!(dir.path().join("ok.txt").exists());
+        assert!(!dir.path().join("later.txt").exists());
+    }
+}
@@ -0,0 +1,82 @@
+use std::{error::Error, io, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use futures::TryStreamExt;
+use http_body_util::{LengthLimitError, Limited};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use super::ArchiveLimits;
+use crate::prelude::*;
+
+/// Refuse uploads larger than `max_upload_bytes`, whether they declare their
+/// length or are cut while being streamed.
+pub async fn limit_upload(
+    State(limits): State<Arc<ArchiveLimits>>,
+    request: Request,
+    next: Next,
+) -> Result<Response> {
+    let max = limits.max_upload_bytes;
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max) {
+        return Err(too_large(max));
+    }
+
+    let request = request.map(|body| Body::new(Limited::new(body, max as usize)));
+    Ok(next.run(request).await)
+}
+
+fn too_large(max: u64) -> AppError {
+    AppError::PayloadTooLarge(f!("upload is larger than {max} bytes"))
+}
+
+/// Read a request body from a blocking thread, as it arrives.
+///
+/// Must be called from within a Tokio runtime, and the reader only used from
+/// `spawn_blocking`.
+pub fn blocking_reader(body: Body) -> impl io::Read + Send + 'static {
+    let stream = body
+        .into_data_stream()
+        .map_err(|e| io::Error::other(e.into_inner()));
+    SyncIoBridge::new(StreamReader::new(stream))
+}
+
+/// Whether reading failed because [`limit_upload`] cut the body.
+pub(super) fn is_over_limit(e: &io::Error) -> bool {
+    let mut source = e.get_ref().map(|e| e as &(dyn Error + 'static));
+    while let Some(e) = source {
+        if e.is::<LengthLimitError>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cut_body_is_over_limit() {
+        let body = Body::new(Limited::new(Body::from(vec![0u8; 2048]), 1024));
+        let mut reader = blocking_reader(body);
+        let e = tokio::task::spawn_blocking(move || io::copy(&mut reader, &mut io::sink()))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(is_over_limit(&e));
+        assert!(matches!(
+            crate::archive::invalid(e),
+            AppError::PayloadTooLarge(_)
+        ));
+    }
+}
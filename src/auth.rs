@@ -0,0 +1,394 @@
+use std::{collections::HashMap, fmt, marker::PhantomData, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{header, request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use crate::{config::Secrets, prelude::*};
+
+/// Prefix of the secrets holding API keys, e.g.
+/// `API_KEY_SANTA = "<sha256 of the key> orders:write admin:reset"`.
+pub const API_KEY_SECRET_PREFIX: &str = "API_KEY_";
+
+/// What an API key is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    /// Create, change and delete orders.
+    OrdersWrite,
+    /// Create, change and delete regions.
+    RegionsWrite,
+    /// Wipe the data of a challenge.
+    AdminReset,
+    /// Mute and kick chat room members.
+    ChatModerate,
+    /// Post chat messages without a WebSocket, under the name of the key.
+    ChatWrite,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::OrdersWrite,
+        Scope::RegionsWrite,
+        Scope::AdminReset,
+        Scope::ChatModerate,
+        Scope::ChatWrite,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::OrdersWrite => "orders:write",
+            Scope::RegionsWrite => "regions:write",
+            Scope::AdminReset => "admin:reset",
+            Scope::ChatModerate => "chat:moderate",
+            Scope::ChatWrite => "chat:write",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| AppError::BadRequest(f!("unknown scope {s:?}")))
+    }
+}
+
+/// The owner of the API key a request was made with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl Principal {
+    /// Used for every request when authentication is disabled.
+    pub fn unrestricted() -> Self {
+        Self {
+            name: "anonymous".to_string(),
+            scopes: Scope::ALL.to_vec(),
+        }
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+/// Hex-encoded SHA-256 of an API key; only hashes are ever stored.
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+#[async_trait]
+pub trait ApiKeyStore: Send + Sync {
+    /// The principal owning the key with this hash, if any.
+    async fn find(&self, key_hash: &str) -> Result<Option<Principal>>;
+}
+
+/// API keys declared in the secrets, see [`API_KEY_SECRET_PREFIX`].
+#[derive(Debug, Clone, Default)]
+pub struct SecretKeyStore {
+    keys: HashMap<String, Principal>,
+}
+
+impl SecretKeyStore {
+    /// Malformed entries are logged and left out.
+    pub fn from_secrets(secrets: &Secrets) -> Self {
+        let mut keys = HashMap::new();
+        for (name, value) in secrets.iter() {
+            let Some(name) = name.strip_prefix(API_KEY_SECRET_PREFIX) else {
+                continue;
+            };
+            let mut fields = value.split_whitespace();
+            let Some(key_hash) = fields.next() else {
+                tracing::warn!("Ignoring API key {}: missing key hash", name);
+                continue;
+            };
+            let scopes = match fields.map(Scope::from_str).collect::<Result<Vec<_>>>() {
+                Ok(scopes) => scopes,
+                Err(e) => {
+                    tracing::warn!("Ignoring API key {}: {}", name, e);
+                    continue;
+                }
+            };
+
+            keys.insert(
+                key_hash.to_lowercase(),
+                Principal {
+                    name: name.to_lowercase(),
+                    scopes,
+                },
+            );
+        }
+
+        Self { keys }
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for SecretKeyStore {
+    async fn find(&self, key_hash: &str) -> Result<Option<Principal>> {
+        Ok(self.keys.get(key_hash).cloned())
+    }
+}
+
+/// API keys stored in the `api_keys` table.
+#[derive(Debug, Clone)]
+pub struct PgKeyStore {
+    db: PgPool,
+}
+
+impl PgKeyStore {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for PgKeyStore {
+    async fn find(&self, key_hash: &str) -> Result<Option<Principal>> {
+        let row = sqlx::query_as::<_, (String, Vec<String>)>(
+            "SELECT name, scopes FROM api_keys WHERE key_hash = $1 AND revoked_at IS NULL",
+        )
+        .bind(key_hash)
+        .fetch_optional(&self.db)
+        .await?;
+
+        row.map(|(name, scopes)| {
+            let scopes = scopes
+                .iter()
+                .map(|s| Scope::from_str(s))
+                .collect::<Result<Vec<_>>>()
+                .map_err(|e| AppError::Internal(anyhow::anyhow!("API key {name}: {e}")))?;
+            Ok(Principal { name, scopes })
+        })
+        .transpose()
+    }
+}
+
+/// Settings and key stores of the [`authenticate`] middleware.
+#[derive(Clone)]
+pub struct Auth {
+    enabled: bool,
+    stores: Arc<[Arc<dyn ApiKeyStore>]>,
+}
+
+impl Auth {
+    pub fn new(enabled: bool, stores: Vec<Arc<dyn ApiKeyStore>>) -> Self {
+        Self {
+            enabled,
+            stores: stores.into(),
+        }
+    }
+
+    /// Authentication is on unless the `AUTH_ENABLED` secret is `false`.
+    ///
+    /// Keys are looked up in the secrets first, then in Postgres.
+    pub fn from_secrets(secrets: &Secrets, db: PgPool) -> Self {
+        let enabled = !secrets
+            .get("AUTH_ENABLED")
+            .is_some_and(|v| v.eq_ignore_ascii_case("false"));
+        if !enabled {
+            tracing::warn!("Authentication is disabled, every request has all the scopes");
+        }
+
+        Self::new(
+            enabled,
+            vec![
+                Arc::new(SecretKeyStore::from_secrets(secrets)),
+                Arc::new(PgKeyStore::new(db)),
+            ],
+        )
+    }
+
+    async fn find(&self, key: &str) -> Result<Option<Principal>> {
+        let key_hash = hash_key(key);
+        for store in self.stores.iter() {
+            if let Some(principal) = store.find(&key_hash).await? {
+                return Ok(Some(principal));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The key from `Authorization: Bearer <key>` or `X-Api-Key: <key>`.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    bearer
+        .or_else(|| headers.get("x-api-key").and_then(|v| v.to_str().ok()))
+        .map(str::trim)
+}
+
+/// Attach the [`Principal`] of the request's API key, if there is one.
+///
+/// Requests without a key go through so that public routes keep working;
+/// handlers require a scope with the [`Authorized`] extractor.
+pub async fn authenticate(
+    State(auth): State<Auth>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response> {
+    if !auth.enabled {
+        request.extensions_mut().insert(Principal::unrestricted());
+    } else if let Some(key) = api_key(request.headers()) {
+        let principal = auth
+            .find(key)
+            .await?
+            .ok_or_else(|| AppError::Unauthorized("invalid API key".to_string()))?;
+        tracing::debug!("Authenticated as {}", principal.name);
+        request.extensions_mut().insert(principal);
+    }
+
+    Ok(next.run(request).await)
+}
+
+/// A scope that handlers can require with [`Authorized`].
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub struct OrdersWrite;
+pub struct RegionsWrite;
+pub struct AdminReset;
+pub struct ChatModerate;
+pub struct ChatWrite;
+
+impl RequiredScope for OrdersWrite {
+    const SCOPE: Scope = Scope::OrdersWrite;
+}
+
+impl RequiredScope for RegionsWrite {
+    const SCOPE: Scope = Scope::RegionsWrite;
+}
+
+impl RequiredScope for AdminReset {
+    const SCOPE: Scope = Scope::AdminReset;
+}
+
+impl RequiredScope for ChatModerate {
+    const SCOPE: Scope = Scope::ChatModerate;
+}
+
+impl RequiredScope for ChatWrite {
+    const SCOPE: Scope = Scope::ChatWrite;
+}
+
+/// Extractor rejecting requests without an API key (401) or whose key lacks
+/// the scope `S` (403).
+pub struct Authorized<S> {
+    pub principal: Principal,
+    _scope: PhantomData<S>,
+}
+
+impl<S> Authorized<S> {
+    pub fn new(principal: Principal) -> Self {
+        Self {
+            principal,
+            _scope: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<S, St> FromRequestParts<St> for Authorized<S>
+where
+    S: RequiredScope,
+    St: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &St) -> Result<Self> {
+        let principal = parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .ok_or_else(|| AppError::Unauthorized("missing API key".to_string()))?;
+        if !principal.has_scope(S::SCOPE) {
+            return Err(AppError::Forbidden(f!(
+                "API key {} lacks the {} scope",
+                principal.name,
+                S::SCOPE
+            )));
+        }
+
+        Ok(Self::new(principal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use axum::{body::Body, http::StatusCode, middleware, routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn reset(_: Authorized<AdminReset>) {}
+
+    fn app(enabled: bool) -> Router {
+        let secrets = Secrets::new(BTreeMap::from([
+            (
+                "API_KEY_SANTA".to_string(),
+                f!("{} admin:reset orders:write", hash_key("santa-key")),
+            ),
+            (
+                "API_KEY_ELF".to_string(),
+                f!("{} orders:write", hash_key("elf-key")),
+            ),
+        ]));
+        let auth = Auth::new(
+            enabled,
+            vec![Arc::new(SecretKeyStore::from_secrets(&secrets))],
+        );
+
+        Router::new()
+            .route("/reset", post(reset))
+            .layer(middleware::from_fn_with_state(auth, authenticate))
+    }
+
+    #[rstest::rstest]
+    #[case(true, None, StatusCode::UNAUTHORIZED)]
+    #[case(true, Some("Bearer wrong-key"), StatusCode::UNAUTHORIZED)]
+    #[case(true, Some("Bearer elf-key"), StatusCode::FORBIDDEN)]
+    #[case(true, Some("Bearer santa-key"), StatusCode::OK)]
+    #[case(false, None, StatusCode::OK)]
+    #[tokio::test]
+    async fn test_reset_requires_scope(
+        #[case] enabled: bool,
+        #[case] authorization: Option<&str>,
+        #[case] expected: StatusCode,
+    ) {
+        let mut request = Request::post("/reset");
+        if let Some(authorization) = authorization {
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        let response = app(enabled)
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), expected);
+    }
+}
@@ -0,0 +1,41 @@
+//! Run the service without the Shuttle runtime.
+//!
+//! See `cch23_xmas::config::Config` for the available settings.
+
+use anyhow::Context;
+use cch23_xmas::{app_state::AppState, config::Config, persist::FilePersist, repo};
+use sqlx::postgres::PgPoolOptions;
+use tracing_subscriber::EnvFilter;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let config = Config::load().context("load config")?;
+    let database_url = config
+        .database_url
+        .as_deref()
+        .context("DATABASE_URL is not configured")?;
+
+    let db_pool = PgPoolOptions::new()
+        .connect(database_url)
+        .await
+        .context("connect to postgres")?;
+    repo::run_migrations(&db_pool)
+        .await
+        .context("run migrations")?;
+
+    let persist = FilePersist::new(&config.persist_dir).context("open persist dir")?;
+    let app_state = AppState::new(config.secrets()?, persist, db_pool);
+
+    let listener = tokio::net::TcpListener::bind(config.bind_addr)
+        .await
+        .with_context(|| format!("bind {}", config.bind_addr))?;
+    tracing::info!("listening on {}", config.bind_addr);
+
+    cch23_xmas::serve(listener, app_state).await
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use ulid::Ulid;
+
+use crate::{config::Secrets, prelude::*, repo::ChatMessage};
+
+pub mod moderation;
+pub mod protocol;
+pub mod rate_limit;
+pub mod views;
+
+use rate_limit::{KeyedTokenBuckets, RateLimit};
+
+/// Number of messages a room buffers for its slowest member.
+pub const ROOM_CAPACITY: usize = 1024;
+
+/// Number of stored messages fetched at once when replaying a room's history.
+pub const HISTORY_PAGE_SIZE: i64 = 100;
+
+/// Limits of the chat rooms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChatConfig {
+    /// Longest accepted message, in characters.
+    pub max_message_chars: usize,
+    /// Messages and typing events a member may send, `None` for no limit.
+    pub user_rate_limit: Option<RateLimit>,
+    /// Messages and typing events sent to a room by all its members.
+    pub room_rate_limit: Option<RateLimit>,
+    /// Times a member may go over its rate limit before being disconnected.
+    pub max_strikes: u32,
+    /// Strikes are forgotten after this long without any.
+    pub strike_window: Duration,
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            max_message_chars: 128,
+            user_rate_limit: Some(RateLimit::new(20, 10.0)),
+            room_rate_limit: Some(RateLimit::new(200, 100.0)),
+            max_strikes: 5,
+            strike_window: Duration::from_secs(10),
+        }
+    }
+}
+
+/// `off` or a [`RateLimit`].
+fn parse_rate_limit(secrets: &Secrets, key: &str) -> Option<Option<RateLimit>> {
+    if secrets
+        .get(key)
+        .is_some_and(|v| v.eq_ignore_ascii_case("off"))
+    {
+        return Some(None);
+    }
+    secrets.parse(key).map(Some)
+}
+
+impl ChatConfig {
+    /// Read from the `CHAT_MAX_MESSAGE_CHARS`, `CHAT_USER_RATE_LIMIT`,
+    /// `CHAT_ROOM_RATE_LIMIT` and `CHAT_MAX_STRIKES` secrets.
+    pub fn from_secrets(secrets: &Secrets) -> Self {
+        let default = Self::default();
+        Self {
+            max_message_chars: secrets
+                .parse("CHAT_MAX_MESSAGE_CHARS")
+                .unwrap_or(default.max_message_chars),
+            user_rate_limit: parse_rate_limit(secrets, "CHAT_USER_RATE_LIMIT")
+                .unwrap_or(default.user_rate_limit),
+            room_rate_limit: parse_rate_limit(secrets, "CHAT_ROOM_RATE_LIMIT")
+                .unwrap_or(default.room_rate_limit),
+            max_strikes: secrets
+                .parse("CHAT_MAX_STRIKES")
+                .unwrap_or(default.max_strikes),
+            ..default
+        }
+    }
+
+    /// Reject messages longer than `max_message_chars`.
+    pub fn check_message(&self, message: &str) -> std::result::Result<(), protocol::FrameError> {
+        let chars = message.chars().count();
+        if chars > self.max_message_chars {
+            return Err(protocol::FrameError::new(
+                protocol::ErrorCode::MessageTooLong,
+                f!(
+                    "message has {chars} characters, at most {} are allowed",
+                    self.max_message_chars
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatroomMessageBody {
+    pub user: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatroomMessage {
+    #[serde(skip)]
+    pub id: Ulid,
+    #[serde(flatten)]
+    pub body: ChatroomMessageBody,
+}
+
+impl From<ChatMessage> for ChatroomMessage {
+    fn from(message: ChatMessage) -> Self {
+        Self {
+            id: message.id,
+            body: ChatroomMessageBody {
+                user: message.user,
+                message: message.message,
+            },
+        }
+    }
+}
+
+/// What the members of a room are told about.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RoomEvent {
+    Message(ChatroomMessage),
+    Join { user: String },
+    Leave { user: String },
+    Typing { user: String },
+}
+
+/// A room with its own broadcast channel, alive while it has members.
+#[derive(Debug)]
+pub struct ChatRoom {
+    id: u64,
+    sender: broadcast::Sender<RoomEvent>,
+    /// Only changed while holding the lock of `ChatRooms`, with the token
+    /// cancelled to kick the member.
+    members: Mutex<BTreeMap<String, CancellationToken>>,
+    messages: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl ChatRoom {
+    fn new(id: u64) -> Self {
+        let (sender, _) = broadcast::channel(ROOM_CAPACITY);
+        Self {
+            id,
+            sender,
+            members: Mutex::default(),
+            messages: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RoomEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Broadcast an event to the members of the room.
+    pub fn send(&self, event: RoomEvent) {
+        if let RoomEvent::Message(_) = event {
+            self.messages.fetch_add(1, Ordering::Relaxed);
+        }
+        // no receiver only means every member has just left
+        let _ = self.sender.send(event);
+    }
+
+    fn lock_members(&self) -> MutexGuard<'_, BTreeMap<String, CancellationToken>> {
+        self.members.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Names of the members, sorted.
+    pub fn members(&self) -> Vec<String> {
+        self.lock_members().keys().cloned().collect()
+    }
+
+    /// Disconnect a member, returns whether it was in the room.
+    pub fn kick(&self, user: &str) -> bool {
+        let Some(kicked) = self.lock_members().get(user).cloned() else {
+            return false;
+        };
+        tracing::debug!("Room {}: User {}: Kicked", self.id, user);
+        kicked.cancel();
+        true
+    }
+
+    /// Count messages a member missed because it could not keep up.
+    pub fn record_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> RoomMetrics {
+        RoomMetrics {
+            room: self.id,
+            members: self.lock_members().len(),
+            messages: self.messages.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RoomMetrics {
+    pub room: u64,
+    pub members: usize,
+    pub messages: u64,
+    /// Messages not delivered to lagging members.
+    pub dropped: u64,
+}
+
+/// The rooms with at least one member, by id.
+#[derive(Debug, Default)]
+pub struct ChatRooms {
+    rooms: Mutex<HashMap<u64, Arc<ChatRoom>>>,
+    /// Events sent to each room, kept while the room has no member.
+    room_limits: KeyedTokenBuckets<u64>,
+    /// Messages posted without a WebSocket, by room and author.
+    poster_limits: KeyedTokenBuckets<(u64, String)>,
+    /// Muted users by room, until the instant if any; kept when rooms are removed.
+    mutes: Mutex<HashMap<(u64, String), Option<Instant>>>,
+}
+
+impl ChatRooms {
+    pub fn new(config: &ChatConfig) -> Self {
+        Self {
+            rooms: Mutex::default(),
+            room_limits: KeyedTokenBuckets::new(config.room_rate_limit),
+            poster_limits: KeyedTokenBuckets::new(config.user_rate_limit),
+            mutes: Mutex::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u64, Arc<ChatRoom>>> {
+        self.rooms.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Join a room, creating it if needed; leave it by dropping the membership.
+    ///
+    /// Fails with `Conflict` if someone with the same name is in the room.
+    pub fn join(self: &Arc<Self>, room_id: u64, user: &str) -> Result<RoomMembership> {
+        let mut rooms = self.lock();
+        let room = rooms
+            .entry(room_id)
+            .or_insert_with(|| {
+                tracing::debug!("Room {}: Created", room_id);
+                Arc::new(ChatRoom::new(room_id))
+            })
+            .clone();
+        let kicked = CancellationToken::new();
+        let mut members = room.lock_members();
+        if members.contains_key(user) {
+            return Err(AppError::Conflict(f!(
+                "{user} is already in room {room_id}"
+            )));
+        }
+        members.insert(user.to_string(), kicked.clone());
+        drop(members);
+        drop(rooms);
+
+        room.send(RoomEvent::Join {
+            user: user.to_string(),
+        });
+        Ok(RoomMembership {
+            rooms: self.clone(),
+            room,
+            user: user.to_string(),
+            kicked,
+        })
+    }
+
+    pub fn get(&self, room_id: u64) -> Option<Arc<ChatRoom>> {
+        self.lock().get(&room_id).cloned()
+    }
+
+    /// Whether the room's rate limit lets one more event through.
+    pub fn try_acquire(&self, room_id: u64) -> bool {
+        self.room_limits.try_take(room_id)
+    }
+
+    /// Whether the rate limits of the author and of the room let one more
+    /// posted message through.
+    pub fn try_acquire_post(&self, room_id: u64, user: &str) -> bool {
+        self.poster_limits.try_take((room_id, user.to_string())) && self.try_acquire(room_id)
+    }
+
+    /// Metrics of every room, sorted by id.
+    pub fn metrics(&self) -> Vec<RoomMetrics> {
+        let mut metrics = self
+            .lock()
+            .values()
+            .map(|room| room.metrics())
+            .collect::<Vec<_>>();
+        metrics.sort_by_key(|m| m.room);
+        metrics
+    }
+
+    fn lock_mutes(&self) -> MutexGuard<'_, HashMap<(u64, String), Option<Instant>>> {
+        self.mutes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Refuse the messages of `user` in a room, for `duration` or until unmuted.
+    pub fn mute(&self, room_id: u64, user: &str, duration: Option<Duration>) -> Result<()> {
+        let until = duration
+            .map(|d| {
+                Instant::now().checked_add(d).ok_or_else(|| {
+                    AppError::BadRequest(f!("cannot mute for {} seconds", d.as_secs()))
+                })
+            })
+            .transpose()?;
+        tracing::debug!("Room {}: User {}: Muted until {:?}", room_id, user, until);
+        self.lock_mutes().insert((room_id, user.to_string()), until);
+        Ok(())
+    }
+
+    /// Returns whether the user was muted.
+    pub fn unmute(&self, room_id: u64, user: &str) -> bool {
+        self.lock_mutes()
+            .remove(&(room_id, user.to_string()))
+            .is_some()
+    }
+
+    pub fn is_muted(&self, room_id: u64, user: &str) -> bool {
+        let mut mutes = self.lock_mutes();
+        let key = (room_id, user.to_string());
+        match mutes.get(&key) {
+            Some(Some(until)) if *until <= Instant::now() => {
+                mutes.remove(&key);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+/// Membership of a room; the room is removed when its last member leaves.
+#[derive(Debug)]
+pub struct RoomMembership {
+    rooms: Arc<ChatRooms>,
+    room: Arc<ChatRoom>,
+    user: String,
+    kicked: CancellationToken,
+}
+
+impl RoomMembership {
+    pub fn room(&self) -> &Arc<ChatRoom> {
+        &self.room
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Cancelled when a moderator kicks the member.
+    pub fn kicked(&self) -> CancellationToken {
+        self.kicked.clone()
+    }
+}
+
+impl Drop for RoomMembership {
+    fn drop(&mut self) {
+        // holding the lock so nobody joins the room while it is being removed
+        let mut rooms = self.rooms.lock();
+        let mut members = self.room.lock_members();
+        members.remove(&self.user);
+        if members.is_empty() {
+            rooms.remove(&self.room.id);
+            tracing::debug!("Room {}: Removed", self.room.id);
+        }
+        drop(members);
+        drop(rooms);
+
+        self.room.send(RoomEvent::Leave {
+            user: std::mem::take(&mut self.user),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_removed_after_last_member_leaves() {
+        let rooms = Arc::new(ChatRooms::default());
+        let alice = rooms.join(1, "alice").unwrap();
+        let bob = rooms.join(1, "bob").unwrap();
+        let _carol = rooms.join(2, "carol").unwrap();
+
+        assert_eq!(
+            rooms
+                .metrics()
+                .iter()
+                .map(|m| m.members)
+                .collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+
+        drop(alice);
+        assert_eq!(rooms.get(1).unwrap().members(), vec!["bob"]);
+        drop(bob);
+        assert!(rooms.get(1).is_none());
+        assert!(rooms.get(2).is_some());
+    }
+
+    #[test]
+    fn test_mute_refuses_unrepresentable_durations() {
+        let rooms = ChatRooms::default();
+
+        assert!(matches!(
+            rooms.mute(1, "grinch", Some(Duration::from_secs(u64::MAX))),
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(!rooms.is_muted(1, "grinch"));
+        rooms
+            .mute(1, "grinch", Some(Duration::from_secs(60)))
+            .unwrap();
+        assert!(rooms.is_muted(1, "grinch"));
+    }
+
+    #[test]
+    fn test_join_rejects_duplicate_names() {
+        let rooms = Arc::new(ChatRooms::default());
+        let alice = rooms.join(1, "alice").unwrap();
+        let mut events = alice.room().subscribe();
+
+        assert!(matches!(rooms.join(1, "alice"), Err(AppError::Conflict(_))));
+        assert!(rooms.join(2, "alice").is_ok());
+
+        let bob = rooms.join(1, "bob").unwrap();
+        drop(bob);
+        let events = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|e| serde_json::to_value(e).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                serde_json::json!({ "type": "join", "user": "bob" }),
+                serde_json::json!({ "type": "leave", "user": "bob" }),
+            ]
+        );
+    }
+}
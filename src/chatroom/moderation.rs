@@ -0,0 +1,197 @@
+use std::{fmt::Debug, str::FromStr};
+
+use regex::Regex;
+
+use super::protocol::{ErrorCode, FrameError};
+use crate::{config::Secrets, prelude::*, utils::escape_html};
+
+/// A step of the [`Moderation`] pipeline.
+pub trait ModerationFilter: Debug + Send + Sync {
+    /// The message to send, possibly rewritten, or why it is rejected.
+    fn apply(&self, message: String) -> std::result::Result<String, FrameError>;
+}
+
+fn rejected(message: impl Into<String>) -> FrameError {
+    FrameError::new(ErrorCode::Rejected, message)
+}
+
+/// What [`Profanity`] does with a message containing a banned word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfanityAction {
+    Reject,
+    /// Replace every character of the word with `*`.
+    Mask,
+}
+
+impl FromStr for ProfanityAction {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "mask" => Ok(Self::Mask),
+            _ => Err(AppError::BadRequest(f!(
+                "unknown profanity action {s:?}, expected reject or mask"
+            ))),
+        }
+    }
+}
+
+/// Rejects or masks whole words of a list, ignoring case.
+#[derive(Debug, Clone)]
+pub struct Profanity {
+    words: Regex,
+    action: ProfanityAction,
+}
+
+impl Profanity {
+    /// `None` without any word.
+    pub fn new<I, S>(words: I, action: ProfanityAction) -> Option<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let words = words
+            .into_iter()
+            .map(|w| regex::escape(w.as_ref().trim()))
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>();
+        if words.is_empty() {
+            return None;
+        }
+        let words = Regex::new(&f!(r"(?i)\b(?:{})\b", words.join("|")))
+            .expect("escaped words make a valid regex");
+        Some(Self { words, action })
+    }
+}
+
+impl ModerationFilter for Profanity {
+    fn apply(&self, message: String) -> std::result::Result<String, FrameError> {
+        if !self.words.is_match(&message) {
+            return Ok(message);
+        }
+        match self.action {
+            ProfanityAction::Reject => Err(rejected("message contains a banned word")),
+            ProfanityAction::Mask => Ok(self
+                .words
+                .replace_all(&message, |c: &regex::Captures| {
+                    "*".repeat(c[0].chars().count())
+                })
+                .into_owned()),
+        }
+    }
+}
+
+/// Replaces URLs with `[link removed]`.
+#[derive(Debug, Clone)]
+pub struct StripLinks {
+    links: Regex,
+}
+
+impl Default for StripLinks {
+    fn default() -> Self {
+        Self {
+            links: Regex::new(r"(?i)\b(?:[a-z][a-z0-9+.-]*://|www\.)\S+")
+                .expect("valid link regex"),
+        }
+    }
+}
+
+impl ModerationFilter for StripLinks {
+    fn apply(&self, message: String) -> std::result::Result<String, FrameError> {
+        Ok(self
+            .links
+            .replace_all(&message, "[link removed]")
+            .into_owned())
+    }
+}
+
+/// Escapes HTML like the `/14/safe` endpoint, for clients rendering messages as HTML.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EscapeHtml;
+
+impl ModerationFilter for EscapeHtml {
+    fn apply(&self, message: String) -> std::result::Result<String, FrameError> {
+        Ok(escape_html(&message).into_owned())
+    }
+}
+
+/// Filters every chat message goes through, in order, before being stored.
+#[derive(Debug, Default)]
+pub struct Moderation {
+    filters: Vec<Box<dyn ModerationFilter>>,
+}
+
+impl Moderation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, filter: impl ModerationFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    /// Read from the `CHAT_BANNED_WORDS` (comma separated),
+    /// `CHAT_PROFANITY_ACTION` (`reject` or `mask`), `CHAT_STRIP_LINKS` and
+    /// `CHAT_ESCAPE_HTML` secrets; messages are left untouched by default.
+    pub fn from_secrets(secrets: &Secrets) -> Self {
+        let mut moderation = Self::new();
+        let action = secrets
+            .parse("CHAT_PROFANITY_ACTION")
+            .unwrap_or(ProfanityAction::Reject);
+        let words = secrets.get("CHAT_BANNED_WORDS").unwrap_or_default();
+        if let Some(profanity) = Profanity::new(words.split(','), action) {
+            moderation = moderation.with(profanity);
+        }
+        if secrets.parse("CHAT_STRIP_LINKS").unwrap_or(false) {
+            moderation = moderation.with(StripLinks::default());
+        }
+        if secrets.parse("CHAT_ESCAPE_HTML").unwrap_or(false) {
+            moderation = moderation.with(EscapeHtml);
+        }
+        moderation
+    }
+
+    /// Run the message through every filter, stopping at the first rejection.
+    pub fn apply(&self, message: String) -> std::result::Result<String, FrameError> {
+        self.filters
+            .iter()
+            .try_fold(message, |message, filter| filter.apply(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn pipeline(action: ProfanityAction) -> Moderation {
+        Moderation::new()
+            .with(Profanity::new(["darn", "heck"], action).unwrap())
+            .with(StripLinks::default())
+            .with(EscapeHtml)
+    }
+
+    #[rstest]
+    #[case("hello <b>world</b>", "hello &lt;b&gt;world&lt;/b&gt;")]
+    #[case("what the HECK", "what the ****")]
+    #[case("checkout is fine", "checkout is fine")]
+    #[case(
+        "see https://example.com/a?b=1 or www.example.org",
+        "see [link removed] or [link removed]"
+    )]
+    fn test_rewrite(#[case] message: &str, #[case] expected: &str) {
+        let moderation = pipeline(ProfanityAction::Mask);
+        assert_eq!(moderation.apply(message.to_string()).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_reject() {
+        let moderation = pipeline(ProfanityAction::Reject);
+        let e = moderation.apply("Darn it".to_string()).unwrap_err();
+        assert_eq!(e.code, ErrorCode::Rejected);
+        assert!(moderation.apply("darnation".to_string()).is_ok());
+    }
+}
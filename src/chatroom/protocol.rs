@@ -0,0 +1,289 @@
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::{ChatroomMessage, ChatroomMessageBody, RoomEvent};
+use crate::prelude::*;
+
+/// Version of the tagged protocol, sent in the `v` field of every frame.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// How a chat client talks to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// `{"message": ...}` in, `{"user": ..., "message": ...}` out, errors only logged.
+    Legacy,
+    /// Tagged and versioned frames, with acks and error frames.
+    V1,
+}
+
+impl Protocol {
+    /// The protocol asked for with `?v=<version>`, legacy without it.
+    pub fn from_version(version: Option<u8>) -> Result<Self> {
+        match version {
+            None => Ok(Protocol::Legacy),
+            Some(PROTOCOL_VERSION) => Ok(Protocol::V1),
+            Some(v) => Err(AppError::BadRequest(f!(
+                "unsupported protocol version {v}, expected {PROTOCOL_VERSION}"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientFrame {
+    Message {
+        /// Chosen by the client, echoed in the ack.
+        #[serde(default)]
+        id: Option<String>,
+        message: String,
+    },
+    Ping {
+        #[serde(default)]
+        id: Option<String>,
+    },
+    Typing,
+}
+
+impl ClientFrame {
+    /// The id the client gave the frame.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            ClientFrame::Message { id, .. } | ClientFrame::Ping { id } => id.as_deref(),
+            ClientFrame::Typing => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientEnvelope {
+    v: u8,
+    #[serde(flatten)]
+    frame: ClientFrame,
+}
+
+/// Chat message sent with the legacy protocol.
+#[derive(Debug, Deserialize)]
+struct LegacyMessage {
+    message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidFrame,
+    UnsupportedVersion,
+    MessageTooLong,
+    RateLimited,
+    /// Refused by the moderation filters.
+    Rejected,
+    /// The user was muted in the room by a moderator.
+    Muted,
+}
+
+/// Why a frame from the client was rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameError {
+    pub id: Option<String>,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl FrameError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            id: None,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Answer the frame with this id.
+    pub fn with_id(self, id: Option<&str>) -> Self {
+        Self {
+            id: id.map(str::to_string),
+            ..self
+        }
+    }
+}
+
+impl Protocol {
+    pub fn parse(&self, text: &str) -> std::result::Result<ClientFrame, FrameError> {
+        match self {
+            Protocol::Legacy => {
+                if let Ok(frame @ ClientFrame::Typing) = serde_json::from_str(text) {
+                    return Ok(frame);
+                }
+                serde_json::from_str::<LegacyMessage>(text)
+                    .map(|m| ClientFrame::Message {
+                        id: None,
+                        message: m.message,
+                    })
+                    .map_err(|e| FrameError::new(ErrorCode::InvalidFrame, e.to_string()))
+            }
+            Protocol::V1 => {
+                let envelope = serde_json::from_str::<ClientEnvelope>(text)
+                    .map_err(|e| FrameError::new(ErrorCode::InvalidFrame, e.to_string()))?;
+                if envelope.v != PROTOCOL_VERSION {
+                    return Err(FrameError::new(
+                        ErrorCode::UnsupportedVersion,
+                        f!("expected version {PROTOCOL_VERSION}, got {}", envelope.v),
+                    ));
+                }
+
+                Ok(envelope.frame)
+            }
+        }
+    }
+}
+
+/// Frames the server sends with the tagged protocol.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerFrame {
+    Message {
+        id: Ulid,
+        user: String,
+        message: String,
+    },
+    Join {
+        user: String,
+    },
+    Leave {
+        user: String,
+    },
+    Typing {
+        user: String,
+    },
+    /// Answers a client message (with the id the server stored it under) or a ping.
+    Ack {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        message_id: Option<Ulid>,
+    },
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl From<RoomEvent> for ServerFrame {
+    fn from(event: RoomEvent) -> Self {
+        match event {
+            RoomEvent::Message(m) => ServerFrame::Message {
+                id: m.id,
+                user: m.body.user,
+                message: m.body.message,
+            },
+            RoomEvent::Join { user } => ServerFrame::Join { user },
+            RoomEvent::Leave { user } => ServerFrame::Leave { user },
+            RoomEvent::Typing { user } => ServerFrame::Typing { user },
+        }
+    }
+}
+
+impl From<FrameError> for ServerFrame {
+    fn from(e: FrameError) -> Self {
+        ServerFrame::Error {
+            id: e.id,
+            code: e.code,
+            message: e.message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ServerEnvelope<'a> {
+    v: u8,
+    #[serde(flatten)]
+    frame: &'a ServerFrame,
+}
+
+impl Protocol {
+    /// The text of a frame, `None` if the frame has no legacy equivalent.
+    ///
+    /// Legacy clients asking for presence get the tagged events without a version.
+    pub fn encode(&self, frame: &ServerFrame, presence: bool) -> Result<Option<String>> {
+        let text = match (self, frame) {
+            (Protocol::V1, frame) => serde_json::to_string(&ServerEnvelope {
+                v: PROTOCOL_VERSION,
+                frame,
+            })?,
+            (Protocol::Legacy, ServerFrame::Message { id, user, message }) => {
+                let body = ChatroomMessageBody {
+                    user: user.clone(),
+                    message: message.clone(),
+                };
+                if presence {
+                    serde_json::to_string(&RoomEvent::Message(ChatroomMessage { id: *id, body }))?
+                } else {
+                    serde_json::to_string(&body)?
+                }
+            }
+            (
+                Protocol::Legacy,
+                ServerFrame::Join { .. } | ServerFrame::Leave { .. } | ServerFrame::Typing { .. },
+            ) if presence => serde_json::to_string(frame)?,
+            (Protocol::Legacy, _) => return Ok(None),
+        };
+
+        Ok(Some(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(Protocol::Legacy, r#"{"message":"hi"}"#, Ok(ClientFrame::Message { id: None, message: "hi".into() }))]
+    #[case(Protocol::Legacy, r#"{"type":"typing"}"#, Ok(ClientFrame::Typing))]
+    #[case(
+        Protocol::V1,
+        r#"{"v":1,"type":"message","id":"c1","message":"hi"}"#,
+        Ok(ClientFrame::Message { id: Some("c1".into()), message: "hi".into() })
+    )]
+    #[case(Protocol::V1, r#"{"v":1,"type":"ping"}"#, Ok(ClientFrame::Ping { id: None }))]
+    #[case(
+        Protocol::V1,
+        r#"{"v":2,"type":"ping"}"#,
+        Err(ErrorCode::UnsupportedVersion)
+    )]
+    #[case(Protocol::V1, r#"{"message":"hi"}"#, Err(ErrorCode::InvalidFrame))]
+    fn test_parse(
+        #[case] protocol: Protocol,
+        #[case] text: &str,
+        #[case] expected: std::result::Result<ClientFrame, ErrorCode>,
+    ) {
+        assert_eq!(protocol.parse(text).map_err(|e| e.code), expected);
+    }
+
+    #[test]
+    fn test_encode() {
+        let id = Ulid::new();
+        let message = ServerFrame::Message {
+            id,
+            user: "alice".into(),
+            message: "hi".into(),
+        };
+        let ack = ServerFrame::Ack {
+            id: Some("c1".into()),
+            message_id: Some(id),
+        };
+
+        assert_eq!(
+            Protocol::Legacy.encode(&message, false).unwrap().unwrap(),
+            r#"{"user":"alice","message":"hi"}"#
+        );
+        assert_eq!(Protocol::Legacy.encode(&ack, true).unwrap(), None);
+        assert_eq!(
+            Protocol::V1.encode(&ack, false).unwrap().unwrap(),
+            f!(r#"{{"v":1,"type":"ack","id":"c1","message_id":"{id}"}}"#)
+        );
+    }
+}
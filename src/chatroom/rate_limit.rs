@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::prelude::*;
+
+/// Allows `burst` frames at once, then `per_second` on average.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    pub fn new(burst: u32, per_second: f64) -> Self {
+        Self { burst, per_second }
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = AppError;
+
+    /// Parse `<burst>/<per second>`, e.g. `20/10`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || {
+            AppError::BadRequest(f!(
+                "invalid rate limit {s:?}, expected <burst>/<per second>"
+            ))
+        };
+        let (burst, per_second) = s.split_once('/').ok_or_else(invalid)?;
+        let burst = burst.trim().parse().map_err(|_| invalid())?;
+        let per_second = per_second.trim().parse::<f64>().map_err(|_| invalid())?;
+        if !per_second.is_finite() || per_second < 0.0 {
+            return Err(invalid());
+        }
+
+        Ok(Self::new(burst, per_second))
+    }
+}
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket.
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst.into(),
+            updated: Instant::now(),
+        }
+    }
+
+    /// Take a token if there is one left.
+    pub fn try_take(&mut self) -> bool {
+        self.try_take_at(Instant::now())
+    }
+
+    fn try_take_at(&mut self, now: Instant) -> bool {
+        self.tokens = self.tokens_at(now);
+        self.updated = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst.into())
+    }
+}
+
+/// Buckets kept before forgetting the full ones, which are the same as new ones.
+const MIN_KEYED_BUCKETS: usize = 1024;
+
+/// A [`TokenBucket`] per key, e.g. per room, created full.
+#[derive(Debug)]
+pub struct KeyedTokenBuckets<K> {
+    limit: Option<RateLimit>,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+impl<K: Hash + Eq> KeyedTokenBuckets<K> {
+    /// Without a limit every token is granted.
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Take a token from the bucket of `key` if there is one left.
+    pub fn try_take(&self, key: K) -> bool {
+        self.try_take_at(key, Instant::now())
+    }
+
+    fn try_take_at(&self, key: K, now: Instant) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MIN_KEYED_BUCKETS && !buckets.contains_key(&key) {
+            let burst = f64::from(limit.burst);
+            buckets.retain(|_, bucket| bucket.tokens_at(now) < burst);
+        }
+        buckets
+            .entry(key)
+            .or_insert_with(|| TokenBucket::new(limit))
+            .try_take_at(now)
+    }
+}
+
+impl<K> Default for KeyedTokenBuckets<K> {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            buckets: Mutex::default(),
+        }
+    }
+}
+
+/// Times a client went over its rate limit, forgotten after `window` without any.
+#[derive(Debug)]
+pub struct Strikes {
+    window: Duration,
+    count: u32,
+    last: Option<Instant>,
+}
+
+impl Strikes {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            count: 0,
+            last: None,
+        }
+    }
+
+    /// Record a strike, returning how many there are.
+    pub fn add(&mut self) -> u32 {
+        self.add_at(Instant::now())
+    }
+
+    fn add_at(&mut self, now: Instant) -> u32 {
+        if self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) > self.window)
+        {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = Some(now);
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("20/10", Some(RateLimit::new(20, 10.0)))]
+    #[case(" 5 / 0.5 ", Some(RateLimit::new(5, 0.5)))]
+    #[case("20", None)]
+    #[case("20/-1", None)]
+    #[case("x/1", None)]
+    fn test_parse_rate_limit(#[case] s: &str, #[case] expected: Option<RateLimit>) {
+        assert_eq!(s.parse::<RateLimit>().ok(), expected);
+    }
+
+    #[test]
+    fn test_token_bucket_refills() {
+        let mut bucket = TokenBucket::new(RateLimit::new(2, 4.0));
+        let start = bucket.updated;
+
+        assert!(bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start));
+        assert!(!bucket.try_take_at(start));
+        assert!(bucket.try_take_at(start + Duration::from_millis(250)));
+        assert!(!bucket.try_take_at(start + Duration::from_millis(300)));
+        // never more than the burst
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.try_take_at(later));
+        assert!(bucket.try_take_at(later));
+        assert!(!bucket.try_take_at(later));
+    }
+
+    #[test]
+    fn test_keyed_buckets_are_separate() {
+        let buckets = KeyedTokenBuckets::new(Some(RateLimit::new(1, 1.0)));
+        let now = Instant::now();
+
+        assert!(buckets.try_take_at(1, now));
+        assert!(!buckets.try_take_at(1, now));
+        assert!(buckets.try_take_at(2, now));
+        assert!(KeyedTokenBuckets::default().try_take_at(1, now));
+    }
+
+    #[test]
+    fn test_strikes_are_forgotten_after_window() {
+        let start = Instant::now();
+        let mut strikes = Strikes::new(Duration::from_secs(10));
+
+        assert_eq!(strikes.add_at(start), 1);
+        assert_eq!(strikes.add_at(start + Duration::from_secs(5)), 2);
+        assert_eq!(strikes.add_at(start + Duration::from_secs(14)), 3);
+        assert_eq!(strikes.add_at(start + Duration::from_secs(30)), 1);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::persist::PersistStore;
+
+/// Key the counts are persisted under.
+pub const VIEWS_KEY: &str = "chat_views";
+
+/// How often changed counts are persisted.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How many times the messages of `user` were delivered in `room`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ViewCount {
+    pub room: u64,
+    pub user: String,
+    pub views: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ViewFilter {
+    pub room: Option<u64>,
+    /// Author of the viewed messages.
+    pub user: Option<String>,
+}
+
+/// Views of chat messages by room and author, kept in memory and persisted
+/// every [`FLUSH_INTERVAL`].
+#[derive(Default)]
+pub struct ViewCounts {
+    counts: Mutex<BTreeMap<(u64, String), u64>>,
+    dirty: AtomicBool,
+    persist: Option<Arc<dyn PersistStore>>,
+}
+
+impl ViewCounts {
+    /// Start from the counts persisted in `persist`, if any.
+    pub fn load(persist: Arc<dyn PersistStore>) -> Self {
+        let counts = match persist.load::<Vec<ViewCount>>(VIEWS_KEY) {
+            Ok(counts) => counts
+                .into_iter()
+                .map(|c| ((c.room, c.user), c.views))
+                .collect(),
+            Err(e) => {
+                tracing::info!("Starting without view counts: {:#}", e);
+                BTreeMap::new()
+            }
+        };
+
+        Self {
+            counts: Mutex::new(counts),
+            dirty: AtomicBool::new(false),
+            persist: Some(persist),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<(u64, String), u64>> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count a message of `user` delivered in `room`.
+    pub fn record(&self, room: u64, user: &str) {
+        *self.lock().entry((room, user.to_string())).or_default() += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Counts matching the filter, by room then user.
+    pub fn counts(&self, filter: &ViewFilter) -> Vec<ViewCount> {
+        self.lock()
+            .iter()
+            .filter(|((room, user), _)| {
+                filter.room.is_none_or(|r| r == *room)
+                    && filter.user.as_ref().is_none_or(|u| u == user)
+            })
+            .map(|((room, user), views)| ViewCount {
+                room: *room,
+                user: user.clone(),
+                views: *views,
+            })
+            .collect()
+    }
+
+    /// Sum of the counts matching the filter.
+    pub fn total(&self, filter: &ViewFilter) -> u64 {
+        self.counts(filter).iter().map(|c| c.views).sum()
+    }
+
+    /// Forget the counts of a room, or of every room.
+    pub fn reset(&self, room: Option<u64>) {
+        let mut counts = self.lock();
+        match room {
+            Some(room) => counts.retain(|(r, _), _| *r != room),
+            None => counts.clear(),
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Persist the counts if they changed since the last flush.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let Some(persist) = &self.persist else {
+            return Ok(());
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let counts = self.counts(&ViewFilter::default());
+        persist.save(VIEWS_KEY, counts).inspect_err(|_| {
+            self.dirty.store(true, Ordering::Relaxed);
+        })
+    }
+
+    /// Flush every [`FLUSH_INTERVAL`] until the counts are dropped.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn spawn_flusher(self: &Arc<Self>) {
+        let views = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(views) = views.upgrade() else {
+                    break;
+                };
+                if let Err(e) = views.flush() {
+                    tracing::error!("Failed to persist view counts: {:#}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::FilePersist;
+
+    #[test]
+    fn test_counts_survive_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist: Arc<dyn PersistStore> = Arc::new(FilePersist::new(dir.path()).unwrap());
+        let views = ViewCounts::load(persist.clone());
+        views.record(1, "alice");
+        views.record(1, "alice");
+        views.record(1, "bob");
+        views.record(2, "alice");
+        views.flush().unwrap();
+
+        let views = ViewCounts::load(persist);
+        let by_user = |user: &str| ViewFilter {
+            room: None,
+            user: Some(user.to_string()),
+        };
+        assert_eq!(views.total(&ViewFilter::default()), 4);
+        assert_eq!(views.total(&by_user("alice")), 3);
+        assert_eq!(
+            views.total(&ViewFilter {
+                room: Some(1),
+                user: Some("alice".to_string()),
+            }),
+            2
+        );
+
+        views.reset(Some(1));
+        assert_eq!(views.total(&by_user("alice")), 1);
+        assert_eq!(views.total(&by_user("bob")), 0);
+    }
+}
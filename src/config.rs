@@ -0,0 +1,133 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+/// Secrets available to the handlers, e.g. third-party API keys.
+///
+/// On Shuttle they come from the deployment's `SecretStore`; the standalone
+/// binary loads them from a `Secrets.toml`-style file and the config file.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Secrets(BTreeMap<String, String>);
+
+impl Secrets {
+    pub fn new(secrets: BTreeMap<String, String>) -> Self {
+        Self(secrets)
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.0.get(key).cloned()
+    }
+
+    /// The secret `key` parsed, invalid values are logged and ignored.
+    pub fn parse<T>(&self, key: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        let value = self.0.get(key)?;
+        value
+            .parse()
+            .map_err(|e| tracing::warn!("Ignoring {} {:?}: {}", key, value, e))
+            .ok()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Read a flat `KEY = "value"` TOML file, the same format Shuttle uses.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("parse {}", path.display()))
+    }
+
+    fn extend(&mut self, other: Secrets) {
+        self.0.extend(other.0);
+    }
+}
+
+#[cfg(feature = "shuttle")]
+impl From<shuttle_secrets::SecretStore> for Secrets {
+    fn from(store: shuttle_secrets::SecretStore) -> Self {
+        Self(store.into_iter().collect())
+    }
+}
+
+/// Configuration of the standalone (non-Shuttle) binary.
+///
+/// Values are read from the TOML file named by `CCH23_CONFIG` (or
+/// `cch23.toml` if it exists) and can be overridden by the `DATABASE_URL`,
+/// `BIND_ADDR`, `PERSIST_DIR` and `SECRETS_FILE` environment variables.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub bind_addr: SocketAddr,
+    pub database_url: Option<String>,
+    pub persist_dir: PathBuf,
+    pub secrets_file: Option<PathBuf>,
+    pub secrets: Secrets,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addr: SocketAddr::from(([127, 0, 0, 1], 8000)),
+            database_url: None,
+            persist_dir: PathBuf::from(".persist"),
+            secrets_file: Some(PathBuf::from("Secrets.toml")),
+            secrets: Secrets::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        let path = env::var_os("CCH23_CONFIG")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from("cch23.toml")).filter(|p| p.exists()));
+
+        let mut config = match path {
+            Some(path) => {
+                let content = fs::read_to_string(&path)
+                    .with_context(|| format!("read {}", path.display()))?;
+                toml::from_str::<Config>(&content)
+                    .with_context(|| format!("parse {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        if let Ok(url) = env::var("DATABASE_URL") {
+            config.database_url = Some(url);
+        }
+        if let Ok(addr) = env::var("BIND_ADDR") {
+            config.bind_addr = addr.parse().context("parse BIND_ADDR")?;
+        }
+        if let Some(dir) = env::var_os("PERSIST_DIR") {
+            config.persist_dir = PathBuf::from(dir);
+        }
+        if let Some(file) = env::var_os("SECRETS_FILE") {
+            config.secrets_file = Some(PathBuf::from(file));
+        }
+
+        Ok(config)
+    }
+
+    /// All secrets: the secrets file first, overridden by the `[secrets]` table.
+    pub fn secrets(&self) -> anyhow::Result<Secrets> {
+        let mut secrets = match &self.secrets_file {
+            Some(path) if path.exists() => Secrets::from_file(path)?,
+            _ => Secrets::default(),
+        };
+        secrets.extend(self.secrets.clone());
+
+        Ok(secrets)
+    }
+}
@@ -1,7 +1,11 @@
 use axum::{
-    http::StatusCode,
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
     response::{IntoResponse, Response},
+    Json,
 };
+use serde::Serialize;
 
 #[derive(thiserror::Error, Debug)]
 pub enum AppError {
@@ -28,27 +32,278 @@ pub enum AppError {
 
     #[error("Bad request: {0}")]
     BadRequest(String),
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+    #[error("Payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+    #[error("Unprocessable entity: {0}")]
+    UnprocessableEntity(String),
     #[error("{{\"result\":\"naughty\",\"reason\":\"{1}\"}}")]
     InvalidPasswordGameInput(StatusCode, String),
     #[error("An internal error occurred: {0}")]
     Internal(anyhow::Error),
 }
 
+/// Header carrying the id generated for every request.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The JSON body of an error response.
+///
+/// `error` is the status (e.g. `not_found`) and `code` a stable,
+/// more specific reason clients can match on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ErrorBody {
+    pub error: String,
+    pub code: &'static str,
+    pub message: String,
+    pub request_id: Option<String>,
+}
+
+impl ErrorBody {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        let error = status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_lowercase()
+            .replace([' ', '-'], "_");
+
+        Self {
+            error,
+            code,
+            message: message.into(),
+            request_id: None,
+        }
+    }
+}
+
+impl AppError {
+    /// Status code and error body, without any internal detail.
+    fn status_and_body(&self) -> (StatusCode, ErrorBody) {
+        use StatusCode as S;
+
+        let internal =
+            |status, code| (status, ErrorBody::new(status, code, "Something went wrong"));
+        let public = |status, code, message: &dyn ToString| {
+            (status, ErrorBody::new(status, code, message.to_string()))
+        };
+
+        match self {
+            AppError::BadRequest(msg) => public(S::BAD_REQUEST, "bad_request", msg),
+            AppError::Unauthorized(msg) => public(S::UNAUTHORIZED, "unauthorized", msg),
+            AppError::Forbidden(msg) => public(S::FORBIDDEN, "forbidden", msg),
+            AppError::NotFound(msg) => public(S::NOT_FOUND, "not_found", msg),
+            AppError::Conflict(msg) => public(S::CONFLICT, "conflict", msg),
+            AppError::TooManyRequests(msg) => {
+                public(S::TOO_MANY_REQUESTS, "too_many_requests", msg)
+            }
+            AppError::PayloadTooLarge(msg) => {
+                public(S::PAYLOAD_TOO_LARGE, "payload_too_large", msg)
+            }
+            AppError::UnsupportedMediaType(msg) => {
+                public(S::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type", msg)
+            }
+            AppError::UnprocessableEntity(msg) => {
+                public(S::UNPROCESSABLE_ENTITY, "unprocessable_entity", msg)
+            }
+            AppError::InvalidPasswordGameInput(status, reason) => {
+                public(*status, "invalid_password", reason)
+            }
+            AppError::NumParseIntError(e) => public(S::BAD_REQUEST, "invalid_integer", e),
+            AppError::ImageError(e) => public(S::BAD_REQUEST, "invalid_image", e),
+            AppError::MultipartError(e) => public(e.status(), "invalid_multipart", e),
+            AppError::SerdeJsonError(e) if !e.is_io() => public(S::BAD_REQUEST, "invalid_json", e),
+            AppError::SqlxError(sqlx::Error::RowNotFound) => {
+                public(S::NOT_FOUND, "not_found", &"Record not found")
+            }
+            AppError::SqlxError(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                public(S::CONFLICT, "unique_violation", &"Record already exists")
+            }
+            AppError::SqlxError(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => {
+                public(
+                    S::CONFLICT,
+                    "foreign_key_violation",
+                    &"Record is referenced or missing",
+                )
+            }
+            AppError::SqlxError(_) | AppError::SqlxMigrationError(_) => {
+                internal(S::INTERNAL_SERVER_ERROR, "database_error")
+            }
+            AppError::ReqwestError(_) | AppError::RequestMiddlewareError(_) => {
+                internal(S::BAD_GATEWAY, "upstream_error")
+            }
+            AppError::SerdeJsonError(_)
+            | AppError::IOError(_)
+            | AppError::AnyhowError(_)
+            | AppError::Internal(_) => internal(S::INTERNAL_SERVER_ERROR, "internal"),
+        }
+    }
+}
+
 // Tell axum how to convert `AppError` into a response.
+//
+// The body is JSON; `render_errors` adds the request id and switches to
+// plain text for clients that ask for it.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        tracing::error!("Application error: {}", self);
+        let (status, body) = self.status_and_body();
+        if status.is_server_error() {
+            tracing::error!("Application error: {:?}", self);
+        } else {
+            tracing::info!("Application error: {}", self);
+        }
 
-        match self {
-            AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg).into_response(),
-            AppError::InvalidPasswordGameInput(status, _) => {
-                (status, self.to_string()).into_response()
-            }
-            _ => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Something went wrong: {}", self),
-            )
-                .into_response(),
+        // the password game checks this exact body
+        if let AppError::InvalidPasswordGameInput(status, _) = self {
+            return (status, self.to_string()).into_response();
         }
+
+        let mut response = (status, Json(body.clone())).into_response();
+        if let AppError::Unauthorized(_) = self {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        response.extensions_mut().insert(body);
+        response
+    }
+}
+
+/// Whether the `Accept` header prefers plain text over JSON.
+fn prefers_plain_text(headers: &HeaderMap) -> bool {
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+
+    accept.contains("text/plain") && !accept.contains("application/json")
+}
+
+/// Middleware completing the error bodies built by `AppError`.
+pub async fn render_errors(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let plain_text = prefers_plain_text(request.headers());
+
+    let response = next.run(request).await;
+    let Some(mut body) = response.extensions().get::<ErrorBody>().cloned() else {
+        return response;
+    };
+    body.request_id = request_id;
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    if plain_text {
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; charset=utf-8"),
+        );
+        Response::from_parts(parts, body.message.into())
+    } else {
+        let json = Json(body).into_response();
+        Response::from_parts(parts, json.into_body())
+    }
+}
+
+impl AppError {
+    /// Turn a unique-key violation into `Conflict`, keeping other database errors as they are.
+    pub fn on_unique_violation(err: sqlx::Error, msg: impl Into<String>) -> Self {
+        match err {
+            sqlx::Error::Database(e) if e.is_unique_violation() => AppError::Conflict(msg.into()),
+            e => AppError::SqlxError(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::to_bytes, routing::get, Router};
+    use rstest::rstest;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[rstest]
+    #[case(
+        AppError::SqlxError(sqlx::Error::RowNotFound),
+        StatusCode::NOT_FOUND,
+        "not_found"
+    )]
+    #[case("x".parse::<i32>().unwrap_err().into(), StatusCode::BAD_REQUEST, "invalid_integer")]
+    #[case(AppError::Conflict("order 1".into()), StatusCode::CONFLICT, "conflict")]
+    #[case(
+        AppError::Internal(anyhow::anyhow!("password=hunter2")),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "internal"
+    )]
+    fn test_status_and_code(
+        #[case] error: AppError,
+        #[case] status: StatusCode,
+        #[case] code: &str,
+    ) {
+        let (actual_status, body) = error.status_and_body();
+        assert_eq!(actual_status, status);
+        assert_eq!(body.code, code);
+        assert!(!body.message.contains("hunter2"));
+    }
+
+    #[test]
+    fn test_unauthorized_asks_for_bearer_token() {
+        let response = AppError::Unauthorized("missing API key".into()).into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    }
+
+    async fn render(accept: &str) -> (StatusCode, String) {
+        let app = Router::new()
+            .route(
+                "/",
+                get(|| async { AppError::NotFound("order 1 not found".to_string()) }),
+            )
+            .layer(axum::middleware::from_fn(render_errors));
+        let request = Request::get("/")
+            .header(header::ACCEPT, accept)
+            .header(REQUEST_ID_HEADER, "42")
+            .body(axum::body::Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_render_errors_as_json() {
+        let (status, body) = render("application/json").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "error": "not_found",
+                "code": "not_found",
+                "message": "order 1 not found",
+                "request_id": "42",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_render_errors_as_plain_text() {
+        let (status, body) = render("text/plain").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, "order 1 not found");
     }
 }
@@ -0,0 +1,252 @@
+use std::time::{Duration, Instant};
+
+/// Why a player's move was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum MoveError {
+    #[error("waiting for an opponent")]
+    WaitingForOpponent,
+    #[error("it is not your serve")]
+    NotYourServe,
+    #[error("the ball is not coming to you")]
+    NotYourTurn,
+    #[error("the game is over")]
+    GameOver,
+}
+
+/// What happened after a move or a timeout.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// The ball is on its way to `to`, who must hit it back before `deadline`.
+    Ball {
+        from: usize,
+        to: usize,
+        rally: u32,
+        deadline: Instant,
+    },
+    /// `winner` scored after a rally of `rally` hits.
+    Point {
+        winner: usize,
+        rally: u32,
+    },
+    GameOver {
+        winner: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Serving {
+        server: usize,
+    },
+    InPlay {
+        to: usize,
+        rally: u32,
+        deadline: Instant,
+    },
+    Over {
+        winner: usize,
+    },
+}
+
+/// The rules of a game between two players, without any I/O.
+///
+/// A player serves, then each player must hit the ball back within the return
+/// timeout; missing it gives the point to the opponent, and the player who
+/// missed serves next.
+#[derive(Debug, Clone)]
+pub struct Game {
+    return_timeout: Duration,
+    points_to_win: u32,
+    score: [u32; 2],
+    longest_rally: u32,
+    state: State,
+}
+
+impl Game {
+    pub fn new(return_timeout: Duration, points_to_win: u32) -> Self {
+        Self {
+            return_timeout,
+            points_to_win: points_to_win.max(1),
+            score: [0, 0],
+            longest_rally: 0,
+            state: State::Serving { server: 0 },
+        }
+    }
+
+    pub fn score(&self) -> [u32; 2] {
+        self.score
+    }
+
+    pub fn longest_rally(&self) -> u32 {
+        self.longest_rally
+    }
+
+    pub fn winner(&self) -> Option<usize> {
+        match self.state {
+            State::Over { winner } => Some(winner),
+            _ => None,
+        }
+    }
+
+    /// Player who must serve, if the ball is not in play.
+    pub fn server(&self) -> Option<usize> {
+        match self.state {
+            State::Serving { server } => Some(server),
+            _ => None,
+        }
+    }
+
+    /// When the ball in play must have been hit back.
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.state {
+            State::InPlay { deadline, .. } => Some(deadline),
+            _ => None,
+        }
+    }
+
+    pub fn serve(&mut self, player: usize, now: Instant) -> Result<Vec<Outcome>, MoveError> {
+        let mut outcomes = self.expire(now);
+        match self.state {
+            State::Serving { server } if server == player => {
+                outcomes.push(self.send_ball(player, 1, now));
+                Ok(outcomes)
+            }
+            State::Over { .. } => Err(MoveError::GameOver),
+            _ if outcomes.is_empty() => Err(MoveError::NotYourServe),
+            // the ball was missed just before the serve arrived
+            _ => Ok(outcomes),
+        }
+    }
+
+    pub fn hit(&mut self, player: usize, now: Instant) -> Result<Vec<Outcome>, MoveError> {
+        let mut outcomes = self.expire(now);
+        match self.state {
+            State::InPlay { to, rally, .. } if to == player => {
+                outcomes.push(self.send_ball(player, rally + 1, now));
+                Ok(outcomes)
+            }
+            State::Over { .. } => Err(MoveError::GameOver),
+            _ if outcomes.is_empty() => Err(MoveError::NotYourTurn),
+            _ => Ok(outcomes),
+        }
+    }
+
+    /// Give the point to the hitter if the ball was not returned in time.
+    pub fn expire(&mut self, now: Instant) -> Vec<Outcome> {
+        let State::InPlay {
+            to,
+            rally,
+            deadline,
+        } = self.state
+        else {
+            return Vec::new();
+        };
+        if now < deadline {
+            return Vec::new();
+        }
+
+        let winner = 1 - to;
+        self.score[winner] += 1;
+        self.longest_rally = self.longest_rally.max(rally);
+        let mut outcomes = vec![Outcome::Point { winner, rally }];
+        if self.score[winner] >= self.points_to_win {
+            self.state = State::Over { winner };
+            outcomes.push(Outcome::GameOver { winner });
+        } else {
+            self.state = State::Serving { server: to };
+        }
+
+        outcomes
+    }
+
+    /// End the game in favour of the opponent of `player`.
+    pub fn forfeit(&mut self, player: usize) -> Vec<Outcome> {
+        if let State::Over { .. } = self.state {
+            return Vec::new();
+        }
+        let winner = 1 - player;
+        self.state = State::Over { winner };
+        vec![Outcome::GameOver { winner }]
+    }
+
+    fn send_ball(&mut self, from: usize, rally: u32, now: Instant) -> Outcome {
+        let to = 1 - from;
+        let deadline = now + self.return_timeout;
+        self.state = State::InPlay {
+            to,
+            rally,
+            deadline,
+        };
+        Outcome::Ball {
+            from,
+            to,
+            rally,
+            deadline,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn test_rally_and_missed_ball() {
+        let start = Instant::now();
+        let mut game = Game::new(TIMEOUT, 2);
+
+        assert_eq!(game.hit(0, start), Err(MoveError::NotYourTurn));
+        assert_eq!(game.serve(1, start), Err(MoveError::NotYourServe));
+        game.serve(0, start).unwrap();
+        game.hit(1, start + TIMEOUT / 2).unwrap();
+        let outcomes = game.hit(0, start + TIMEOUT).unwrap();
+        assert!(matches!(
+            outcomes[..],
+            [Outcome::Ball {
+                to: 1,
+                rally: 3,
+                ..
+            }]
+        ));
+
+        // player 1 misses, player 0 scores and player 1 serves
+        let late = start + TIMEOUT * 3;
+        assert_eq!(
+            game.hit(1, late),
+            Ok(vec![Outcome::Point {
+                winner: 0,
+                rally: 3
+            }])
+        );
+        assert_eq!(game.score(), [1, 0]);
+        assert_eq!(game.server(), Some(1));
+        assert_eq!(game.longest_rally(), 3);
+    }
+
+    #[test]
+    fn test_game_over() {
+        let start = Instant::now();
+        let mut game = Game::new(TIMEOUT, 2);
+
+        game.serve(0, start).unwrap();
+        game.expire(start + TIMEOUT);
+        let later = start + TIMEOUT * 2;
+        game.serve(1, later).unwrap();
+        game.hit(0, later).unwrap();
+        assert_eq!(
+            game.expire(later + TIMEOUT),
+            vec![
+                Outcome::Point {
+                    winner: 0,
+                    rally: 2
+                },
+                Outcome::GameOver { winner: 0 },
+            ]
+        );
+        assert_eq!(game.winner(), Some(0));
+        assert_eq!(game.serve(1, later), Err(MoveError::GameOver));
+        assert_eq!(game.forfeit(0), vec![]);
+    }
+}
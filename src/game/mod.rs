@@ -0,0 +1,576 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use ulid::Ulid;
+
+use crate::{config::Secrets, persist::PersistStore, prelude::*};
+
+pub mod engine;
+
+use engine::{Game, MoveError, Outcome};
+
+/// Key the leaderboard is persisted under.
+pub const LEADERBOARD_KEY: &str = "game_leaderboard";
+
+/// Rules of the games.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameConfig {
+    /// How long a player has to hit the ball back.
+    pub return_timeout: Duration,
+    pub points_to_win: u32,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            return_timeout: Duration::from_secs(3),
+            points_to_win: 5,
+        }
+    }
+}
+
+impl GameConfig {
+    /// Read from the `GAME_RETURN_TIMEOUT_MS` and `GAME_POINTS_TO_WIN` secrets.
+    pub fn from_secrets(secrets: &Secrets) -> Self {
+        let default = Self::default();
+        Self {
+            return_timeout: secrets
+                .parse("GAME_RETURN_TIMEOUT_MS")
+                .map(Duration::from_millis)
+                .unwrap_or(default.return_timeout),
+            points_to_win: secrets
+                .parse("GAME_POINTS_TO_WIN")
+                .unwrap_or(default.points_to_win),
+        }
+    }
+}
+
+/// Frames sent by the players.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Serve,
+    Hit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidFrame,
+    WaitingForOpponent,
+    NotYourServe,
+    NotYourTurn,
+    GameOver,
+}
+
+impl From<MoveError> for ErrorCode {
+    fn from(e: MoveError) -> Self {
+        match e {
+            MoveError::WaitingForOpponent => ErrorCode::WaitingForOpponent,
+            MoveError::NotYourServe => ErrorCode::NotYourServe,
+            MoveError::NotYourTurn => ErrorCode::NotYourTurn,
+            MoveError::GameOver => ErrorCode::GameOver,
+        }
+    }
+}
+
+/// Frames sent to the players.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// Sent to a player joining the session, pass the id to an opponent.
+    Session {
+        session: Ulid,
+        players: Vec<String>,
+    },
+    Start {
+        players: Vec<String>,
+        serving: String,
+        points_to_win: u32,
+    },
+    Ball {
+        from: String,
+        to: String,
+        rally: u32,
+        timeout_ms: u64,
+    },
+    Point {
+        winner: String,
+        rally: u32,
+        score: BTreeMap<String, u32>,
+        /// Who serves next, unless the game is over.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        serving: Option<String>,
+    },
+    GameOver {
+        winner: String,
+        score: BTreeMap<String, u32>,
+        longest_rally: u32,
+        /// The loser left before the end.
+        forfeit: bool,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+impl ServerFrame {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        ServerFrame::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlayerStats {
+    pub player: String,
+    pub wins: u32,
+    pub losses: u32,
+    /// Points scored over all games.
+    pub points: u32,
+    pub longest_rally: u32,
+}
+
+/// Results of the finished games by player, persisted after every game.
+#[derive(Default)]
+pub struct Leaderboard {
+    stats: Mutex<HashMap<String, PlayerStats>>,
+    persist: Option<Arc<dyn PersistStore>>,
+}
+
+impl Leaderboard {
+    /// Start from the leaderboard persisted in `persist`, if any.
+    pub fn load(persist: Arc<dyn PersistStore>) -> Self {
+        let stats = match persist.load::<Vec<PlayerStats>>(LEADERBOARD_KEY) {
+            Ok(stats) => stats.into_iter().map(|s| (s.player.clone(), s)).collect(),
+            Err(e) => {
+                tracing::info!("Starting with an empty leaderboard: {:#}", e);
+                HashMap::new()
+            }
+        };
+
+        Self {
+            stats: Mutex::new(stats),
+            persist: Some(persist),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, PlayerStats>> {
+        self.stats.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Add the result of a game between `players`.
+    pub fn record(&self, players: &[String; 2], game: &Game) {
+        let Some(winner) = game.winner() else {
+            return;
+        };
+        let mut stats = self.lock();
+        for (i, player) in players.iter().enumerate() {
+            let entry = stats.entry(player.clone()).or_insert_with(|| PlayerStats {
+                player: player.clone(),
+                ..Default::default()
+            });
+            if i == winner {
+                entry.wins += 1;
+            } else {
+                entry.losses += 1;
+            }
+            entry.points += game.score()[i];
+            entry.longest_rally = entry.longest_rally.max(game.longest_rally());
+        }
+
+        let Some(persist) = &self.persist else {
+            return;
+        };
+        let all = stats.values().cloned().collect::<Vec<_>>();
+        drop(stats);
+        if let Err(e) = persist.save(LEADERBOARD_KEY, all) {
+            tracing::error!("Failed to persist the leaderboard: {:#}", e);
+        }
+    }
+
+    /// The best `limit` players: most wins, then most points.
+    pub fn top(&self, limit: usize) -> Vec<PlayerStats> {
+        let mut stats = self.lock().values().cloned().collect::<Vec<_>>();
+        stats.sort_by(|a, b| {
+            b.wins
+                .cmp(&a.wins)
+                .then(b.points.cmp(&a.points))
+                .then_with(|| a.player.cmp(&b.player))
+        });
+        stats.truncate(limit);
+        stats
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Join {
+        player: String,
+        events: mpsc::UnboundedSender<ServerFrame>,
+        joined: oneshot::Sender<Result<()>>,
+    },
+    Play {
+        player: String,
+        frame: ClientFrame,
+    },
+    Leave {
+        player: String,
+    },
+}
+
+/// The open game sessions, each run by its own task.
+pub struct Games {
+    config: GameConfig,
+    leaderboard: Arc<Leaderboard>,
+    sessions: Mutex<HashMap<Ulid, mpsc::UnboundedSender<Command>>>,
+}
+
+impl Games {
+    pub fn new(config: GameConfig, leaderboard: Arc<Leaderboard>) -> Self {
+        Self {
+            config,
+            leaderboard,
+            sessions: Mutex::default(),
+        }
+    }
+
+    pub fn leaderboard(&self) -> &Leaderboard {
+        &self.leaderboard
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Ulid, mpsc::UnboundedSender<Command>>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Open a session waiting for two players.
+    pub fn create(self: &Arc<Self>) -> Ulid {
+        let id = Ulid::new();
+        let (commands, rx) = mpsc::unbounded_channel();
+        self.lock().insert(id, commands);
+        tracing::debug!("Game {}: Created", id);
+
+        let session = Session {
+            id,
+            games: self.clone(),
+            players: Vec::new(),
+            game: None,
+        };
+        tokio::spawn(session.run(rx));
+        id
+    }
+
+    /// Join a session; leave it by dropping the player.
+    ///
+    /// Fails with `NotFound` for unknown or finished sessions and with
+    /// `Conflict` if the session is full or the name taken.
+    pub async fn join(&self, session: Ulid, player: &str) -> Result<GamePlayer> {
+        let not_found = || AppError::NotFound(f!("game session {session} not found"));
+        let commands = self.lock().get(&session).cloned().ok_or_else(not_found)?;
+
+        let (events, rx) = mpsc::unbounded_channel();
+        let (joined, joined_rx) = oneshot::channel();
+        let join = Command::Join {
+            player: player.to_string(),
+            events,
+            joined,
+        };
+        commands.send(join).map_err(|_| not_found())?;
+        joined_rx.await.map_err(|_| not_found())??;
+
+        Ok(GamePlayer {
+            session,
+            player: player.to_string(),
+            commands,
+            events: rx,
+        })
+    }
+}
+
+/// A player in a session, leaving it (and forfeiting) when dropped.
+#[derive(Debug)]
+pub struct GamePlayer {
+    session: Ulid,
+    player: String,
+    commands: mpsc::UnboundedSender<Command>,
+    events: mpsc::UnboundedReceiver<ServerFrame>,
+}
+
+impl GamePlayer {
+    pub fn session(&self) -> Ulid {
+        self.session
+    }
+
+    pub fn play(&self, frame: ClientFrame) {
+        // the session is over, its final frames are still queued
+        let _ = self.commands.send(Command::Play {
+            player: self.player.clone(),
+            frame,
+        });
+    }
+
+    /// The next frame for the player, `None` once the session is over.
+    pub async fn next_frame(&mut self) -> Option<ServerFrame> {
+        self.events.recv().await
+    }
+}
+
+impl Drop for GamePlayer {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Leave {
+            player: std::mem::take(&mut self.player),
+        });
+    }
+}
+
+/// State of a session, owned by its task.
+struct Session {
+    id: Ulid,
+    games: Arc<Games>,
+    players: Vec<(String, mpsc::UnboundedSender<ServerFrame>)>,
+    game: Option<Game>,
+}
+
+impl Session {
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Command>) {
+        loop {
+            let deadline = self.game.as_ref().and_then(Game::deadline);
+            let command = tokio::select! {
+                command = commands.recv() => command,
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()), if deadline.is_some() => {
+                    let outcomes = self.game.as_mut().map(|g| g.expire(Instant::now())).unwrap_or_default();
+                    self.publish(outcomes, false);
+                    if self.is_over() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            // the registry keeps a sender until the session is over
+            let Some(command) = command else {
+                break;
+            };
+
+            match command {
+                Command::Join {
+                    player,
+                    events,
+                    joined,
+                } => {
+                    let result = self.join(player, events);
+                    let _ = joined.send(result);
+                }
+                Command::Play { player, frame } => self.play(&player, frame),
+                Command::Leave { player } => self.leave(&player),
+            }
+            if self.is_over() || self.players.is_empty() {
+                break;
+            }
+        }
+
+        self.games.lock().remove(&self.id);
+        tracing::debug!("Game {}: Removed", self.id);
+    }
+
+    fn is_over(&self) -> bool {
+        self.game.as_ref().is_some_and(|g| g.winner().is_some())
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.players.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn index(&self, player: &str) -> Option<usize> {
+        self.players.iter().position(|(name, _)| name == player)
+    }
+
+    fn send(&self, player: usize, frame: ServerFrame) {
+        // a player that is gone is told to leave soon
+        let _ = self.players[player].1.send(frame);
+    }
+
+    fn broadcast(&self, frame: ServerFrame) {
+        for player in 0..self.players.len() {
+            self.send(player, frame.clone());
+        }
+    }
+
+    fn join(&mut self, player: String, events: mpsc::UnboundedSender<ServerFrame>) -> Result<()> {
+        if self.index(&player).is_some() {
+            return Err(AppError::Conflict(f!(
+                "{player} is already in game session {}",
+                self.id
+            )));
+        }
+        if self.players.len() == 2 {
+            return Err(AppError::Conflict(f!("game session {} is full", self.id)));
+        }
+
+        tracing::debug!("Game {}: {} joined", self.id, player);
+        self.players.push((player, events));
+        self.broadcast(ServerFrame::Session {
+            session: self.id,
+            players: self.names(),
+        });
+        if self.players.len() == 2 {
+            let config = &self.games.config;
+            let game = Game::new(config.return_timeout, config.points_to_win);
+            self.broadcast(ServerFrame::Start {
+                players: self.names(),
+                serving: self.players[game.server().unwrap_or_default()].0.clone(),
+                points_to_win: config.points_to_win,
+            });
+            self.game = Some(game);
+        }
+
+        Ok(())
+    }
+
+    fn play(&mut self, player: &str, frame: ClientFrame) {
+        let Some(index) = self.index(player) else {
+            return;
+        };
+        let Some(game) = &mut self.game else {
+            let e = MoveError::WaitingForOpponent;
+            self.send(index, ServerFrame::error(e.into(), e.to_string()));
+            return;
+        };
+
+        let now = Instant::now();
+        let result = match frame {
+            ClientFrame::Serve => game.serve(index, now),
+            ClientFrame::Hit => game.hit(index, now),
+        };
+        match result {
+            Ok(outcomes) => self.publish(outcomes, false),
+            Err(e) => self.send(index, ServerFrame::error(e.into(), e.to_string())),
+        }
+    }
+
+    fn leave(&mut self, player: &str) {
+        let Some(index) = self.index(player) else {
+            return;
+        };
+        tracing::debug!("Game {}: {} left", self.id, player);
+        match &mut self.game {
+            Some(game) => {
+                let outcomes = game.forfeit(index);
+                self.publish(outcomes, true);
+            }
+            None => {
+                self.players.remove(index);
+            }
+        }
+    }
+
+    fn publish(&self, outcomes: Vec<Outcome>, forfeit: bool) {
+        let Some(game) = &self.game else {
+            return;
+        };
+        let name = |i: usize| self.players[i].0.clone();
+        let score = || {
+            (0..2)
+                .map(|i| (name(i), game.score()[i]))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        for outcome in outcomes {
+            let frame = match outcome {
+                Outcome::Ball {
+                    from, to, rally, ..
+                } => ServerFrame::Ball {
+                    from: name(from),
+                    to: name(to),
+                    rally,
+                    timeout_ms: self.games.config.return_timeout.as_millis() as u64,
+                },
+                Outcome::Point { winner, rally } => ServerFrame::Point {
+                    winner: name(winner),
+                    rally,
+                    score: score(),
+                    serving: game.server().map(name),
+                },
+                Outcome::GameOver { winner } => {
+                    let players = [name(0), name(1)];
+                    self.games.leaderboard.record(&players, game);
+                    tracing::debug!("Game {}: {} won", self.id, players[winner]);
+                    ServerFrame::GameOver {
+                        winner: name(winner),
+                        score: score(),
+                        longest_rally: game.longest_rally(),
+                        forfeit,
+                    }
+                }
+            };
+            self.broadcast(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_two_players_play_until_game_over() {
+        let config = GameConfig {
+            return_timeout: Duration::from_millis(50),
+            points_to_win: 1,
+        };
+        let games = Arc::new(Games::new(config, Arc::default()));
+        let session = games.create();
+        let mut alice = games.join(session, "alice").await.unwrap();
+        assert!(matches!(
+            games.join(session, "alice").await,
+            Err(AppError::Conflict(_))
+        ));
+        let mut bob = games.join(session, "bob").await.unwrap();
+        assert!(matches!(
+            games.join(session, "carol").await,
+            Err(AppError::Conflict(_))
+        ));
+
+        alice.play(ClientFrame::Serve);
+        bob.play(ClientFrame::Serve);
+        // bob does not hit the ball back
+        let mut frames = Vec::new();
+        while let Some(frame) = bob.next_frame().await {
+            frames.push(frame);
+        }
+        assert!(matches!(
+            &frames[..],
+            [
+                ServerFrame::Session { .. },
+                ServerFrame::Start { .. },
+                ServerFrame::Ball { rally: 1, .. },
+                ServerFrame::Error {
+                    code: ErrorCode::NotYourServe,
+                    ..
+                },
+                ServerFrame::Point { serving: None, .. },
+                ServerFrame::GameOver { forfeit: false, .. },
+            ]
+        ));
+        while alice.next_frame().await.is_some() {}
+
+        let top = games.leaderboard().top(10);
+        assert_eq!(
+            top.iter()
+                .map(|s| (s.player.as_str(), s.wins, s.losses))
+                .collect::<Vec<_>>(),
+            vec![("alice", 1, 0), ("bob", 0, 1)]
+        );
+        assert!(matches!(
+            games.join(session, "carol").await,
+            Err(AppError::NotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use git2::{BranchType, Commit, Diff, Patch, Repository};
+use serde::Serialize;
+
+use crate::prelude::*;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuthorStats {
+    pub name: String,
+    pub email: String,
+    pub commits: usize,
+    pub additions: usize,
+    pub deletions: usize,
+    pub first_commit: DateTime<Utc>,
+    pub last_commit: DateTime<Utc>,
+}
+
+/// Lines changed by a commit, compared with its parent.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CommitChurn {
+    pub commit: String,
+    pub author: String,
+    pub time: DateTime<Utc>,
+    pub summary: String,
+    pub additions: usize,
+    pub deletions: usize,
+    pub files_changed: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileChurn {
+    pub path: String,
+    /// Commits changing the file.
+    pub commits: usize,
+    pub additions: usize,
+    pub deletions: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RefTarget {
+    pub name: String,
+    pub commit: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+}
+
+/// A commit changing the path asked for.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PathChange {
+    pub commit: String,
+    pub author: String,
+    pub time: DateTime<Utc>,
+    pub summary: String,
+    pub change: Change,
+    pub additions: usize,
+    pub deletions: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RepoAnalytics {
+    /// Most commits first.
+    pub authors: Vec<AuthorStats>,
+    /// Newest first, up to the limit.
+    pub commits: Vec<CommitChurn>,
+    /// Changed by the most commits first, up to the limit.
+    pub files: Vec<FileChurn>,
+    pub branches: Vec<RefTarget>,
+    pub tags: Vec<RefTarget>,
+    /// Newest first, only when a path was asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_history: Option<Vec<PathChange>>,
+}
+
+/// What to report about the history of a ref.
+#[derive(Debug, Clone, Default)]
+pub struct AnalyticsOptions {
+    /// Path to report the history of.
+    pub path: Option<String>,
+    /// Most commits listed, the stats cover every commit.
+    pub commits: usize,
+    /// Most files listed.
+    pub files: usize,
+}
+
+/// Changes of one file in one commit.
+struct FileDiff {
+    path: String,
+    change: Change,
+    additions: usize,
+    deletions: usize,
+}
+
+/// Walk the commits reachable from `from`, diffing each with its parent.
+///
+/// Merge commits are counted for their author but not diffed, like `git log`.
+pub fn analyze(
+    repo: &Repository,
+    from: &Commit<'_>,
+    options: &AnalyticsOptions,
+) -> Result<RepoAnalytics> {
+    let mut authors: HashMap<(String, String), AuthorStats> = HashMap::new();
+    let mut files: HashMap<String, FileChurn> = HashMap::new();
+    let mut commits = Vec::new();
+    let mut path_history = options.path.as_ref().map(|_| Vec::new());
+
+    for commit in super::history(repo, from)? {
+        let commit = commit?;
+        let diffs = if commit.parent_count() > 1 {
+            Vec::new()
+        } else {
+            diff_with_parent(repo, &commit)?
+        };
+        let author = commit.author();
+        let name = String::from_utf8_lossy(author.name_bytes()).into_owned();
+        let time = super::authored_at(&commit);
+        let summary =
+            String::from_utf8_lossy(commit.summary_bytes().unwrap_or_default()).into_owned();
+        let additions = diffs.iter().map(|d| d.additions).sum();
+        let deletions = diffs.iter().map(|d| d.deletions).sum();
+
+        let email = String::from_utf8_lossy(author.email_bytes()).into_owned();
+        let stats = authors
+            .entry((name.clone(), email.clone()))
+            .or_insert_with(|| AuthorStats {
+                name: name.clone(),
+                email,
+                commits: 0,
+                additions: 0,
+                deletions: 0,
+                first_commit: time,
+                last_commit: time,
+            });
+        stats.commits += 1;
+        stats.additions += additions;
+        stats.deletions += deletions;
+        stats.first_commit = stats.first_commit.min(time);
+        stats.last_commit = stats.last_commit.max(time);
+
+        for diff in &diffs {
+            let file = files.entry(diff.path.clone()).or_insert_with(|| FileChurn {
+                path: diff.path.clone(),
+                commits: 0,
+                additions: 0,
+                deletions: 0,
+            });
+            file.commits += 1;
+            file.additions += diff.additions;
+            file.deletions += diff.deletions;
+        }
+
+        if let (Some(history), Some(path)) = (path_history.as_mut(), options.path.as_ref()) {
+            if let Some(diff) = diffs.iter().find(|d| &d.path == path) {
+                history.push(PathChange {
+                    commit: commit.id().to_string(),
+                    author: name.clone(),
+                    time,
+                    summary: summary.clone(),
+                    change: diff.change,
+                    additions: diff.additions,
+                    deletions: diff.deletions,
+                });
+            }
+        }
+
+        if commits.len() < options.commits {
+            commits.push(CommitChurn {
+                commit: commit.id().to_string(),
+                author: name,
+                time,
+                summary,
+                additions,
+                deletions,
+                files_changed: diffs.len(),
+            });
+        }
+    }
+
+    let mut authors = authors.into_values().collect::<Vec<_>>();
+    authors.sort_by(|a, b| b.commits.cmp(&a.commits).then_with(|| a.name.cmp(&b.name)));
+    let mut files = files.into_values().collect::<Vec<_>>();
+    files.sort_by(|a, b| {
+        b.commits
+            .cmp(&a.commits)
+            .then_with(|| (b.additions + b.deletions).cmp(&(a.additions + a.deletions)))
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    files.truncate(options.files);
+
+    Ok(RepoAnalytics {
+        authors,
+        commits,
+        files,
+        branches: branches(repo)?,
+        tags: tags(repo)?,
+        path_history,
+    })
+}
+
+fn diff_with_parent(repo: &Repository, commit: &Commit<'_>) -> Result<Vec<FileDiff>> {
+    let tree = commit.tree().context("get commit tree")?;
+    let parent = match commit.parent(0) {
+        Ok(parent) => Some(parent.tree().context("get parent tree")?),
+        Err(_) => None,
+    };
+    let diff = repo
+        .diff_tree_to_tree(parent.as_ref(), Some(&tree), None)
+        .context("diff commit")?;
+    file_diffs(&diff)
+}
+
+fn file_diffs(diff: &Diff<'_>) -> Result<Vec<FileDiff>> {
+    let mut files = Vec::new();
+    for (i, delta) in diff.deltas().enumerate() {
+        let change = match delta.status() {
+            git2::Delta::Added => Change::Added,
+            git2::Delta::Deleted => Change::Deleted,
+            _ => Change::Modified,
+        };
+        let file = match change {
+            Change::Deleted => delta.old_file(),
+            _ => delta.new_file(),
+        };
+        let path = file
+            .path()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        // binary files have no patch
+        let (additions, deletions) = match Patch::from_diff(diff, i).context("diff file")? {
+            Some(patch) => {
+                let (_, additions, deletions) = patch.line_stats().context("count lines")?;
+                (additions, deletions)
+            }
+            None => (0, 0),
+        };
+        files.push(FileDiff {
+            path,
+            change,
+            additions,
+            deletions,
+        });
+    }
+
+    Ok(files)
+}
+
+/// Local branches, sorted by name.
+fn branches(repo: &Repository) -> Result<Vec<RefTarget>> {
+    let mut branches = Vec::new();
+    for branch in repo
+        .branches(Some(BranchType::Local))
+        .context("list branches")?
+    {
+        let (branch, _) = branch.context("read branch")?;
+        let (Some(name), Ok(commit)) =
+            (branch.name().ok().flatten(), branch.get().peel_to_commit())
+        else {
+            continue;
+        };
+        branches.push(RefTarget {
+            name: name.to_string(),
+            commit: commit.id().to_string(),
+        });
+    }
+    branches.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(branches)
+}
+
+/// Tags pointing to commits, sorted by name.
+fn tags(repo: &Repository) -> Result<Vec<RefTarget>> {
+    let mut tags = Vec::new();
+    for reference in repo.references_glob("refs/tags/*").context("list tags")? {
+        let reference = reference.context("read tag")?;
+        let (Some(name), Ok(commit)) = (reference.shorthand(), reference.peel_to_commit()) else {
+            continue;
+        };
+        tags.push(RefTarget {
+            name: name.to_string(),
+            commit: commit.id().to_string(),
+        });
+    }
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::tests::commit;
+
+    #[test]
+    fn test_analyze() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit(
+            &repo,
+            &[("santa.txt", "milk\n"), ("list.txt", "a\nb\n")],
+            "Alice",
+            1_000,
+        );
+        commit(&repo, &[("santa.txt", "milk\nCOOKIE\n")], "Bob", 2_000);
+        commit(&repo, &[("santa.txt", "COOKIE\n")], "Alice", 3_000);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.tag_lightweight("v1", head.as_object(), false).unwrap();
+
+        let options = AnalyticsOptions {
+            path: Some("santa.txt".to_string()),
+            commits: 2,
+            files: 10,
+        };
+        let analytics = analyze(&repo, &head, &options).unwrap();
+        assert_eq!(
+            analytics
+                .authors
+                .iter()
+                .map(|a| (a.name.as_str(), a.commits, a.additions, a.deletions))
+                .collect::<Vec<_>>(),
+            vec![("Alice", 2, 3, 1), ("Bob", 1, 1, 0)]
+        );
+        assert_eq!(analytics.commits.len(), 2);
+        assert_eq!(analytics.commits[0].deletions, 1);
+        assert_eq!(
+            analytics
+                .files
+                .iter()
+                .map(|f| (f.path.as_str(), f.commits))
+                .collect::<Vec<_>>(),
+            vec![("santa.txt", 3), ("list.txt", 1)]
+        );
+        assert_eq!(analytics.tags[0].name, "v1");
+        assert_eq!(analytics.branches.len(), 1);
+        let history = analytics.path_history.unwrap();
+        assert_eq!(
+            history.iter().map(|c| c.change).collect::<Vec<_>>(),
+            vec![Change::Modified, Change::Modified, Change::Added]
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use std::{io::Read, path::Path};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use git2::{Commit, Repository};
+use tempfile::TempDir;
+
+use crate::{
+    archive::{self, ArchiveLimits},
+    prelude::*,
+};
+
+pub mod analytics;
+pub mod search;
+
+/// A repository unpacked from an uploaded archive, deleted when dropped.
+pub struct UploadedRepo {
+    repo: Repository,
+    _dir: TempDir,
+}
+
+impl UploadedRepo {
+    /// Safely unpack a tar archive holding a repository at its root.
+    pub fn unpack<R: Read>(tar: R, limits: &ArchiveLimits) -> Result<Self> {
+        let dir = TempDir::new()?;
+        archive::unpack(tar, dir.path(), limits)?;
+        let repo = open(dir.path())?;
+        Ok(Self { repo, _dir: dir })
+    }
+
+    pub fn repo(&self) -> &Repository {
+        &self.repo
+    }
+}
+
+/// Files of a git directory that make git read objects from elsewhere.
+const REDIRECTS: [&str; 2] = ["commondir", "objects/info/alternates"];
+
+/// Open the repository at the root of an unpacked archive, refusing anything
+/// that would make git read outside of it.
+fn open(path: &Path) -> Result<Repository> {
+    let git_dir = path.join(".git");
+    // a `.git` file could point to any repository on the server
+    if !std::fs::symlink_metadata(&git_dir).is_ok_and(|meta| meta.is_dir()) {
+        return Err(AppError::BadRequest(
+            "archive is not a git repository: .git is not a directory".to_string(),
+        ));
+    }
+    if let Some(redirect) = REDIRECTS
+        .iter()
+        .find(|file| std::fs::symlink_metadata(git_dir.join(file)).is_ok())
+    {
+        return Err(AppError::UnprocessableEntity(f!(
+            "repository must not have a .git/{redirect} file"
+        )));
+    }
+
+    Repository::open_bare(&git_dir)
+        .map_err(|e| AppError::BadRequest(f!("archive is not a git repository: {}", e.message())))
+}
+
+/// The commit a branch, tag, or any revision `git rev-parse` understands points to.
+pub fn resolve<'r>(repo: &'r Repository, reference: &str) -> Result<Commit<'r>> {
+    repo.revparse_single(reference)
+        .and_then(|object| object.peel_to_commit())
+        .map_err(|e| AppError::NotFound(f!("unknown ref {reference:?}: {}", e.message())))
+}
+
+/// Commits reachable from `from`, newest first.
+pub fn history<'r>(
+    repo: &'r Repository,
+    from: &Commit<'_>,
+) -> Result<impl Iterator<Item = Result<Commit<'r>>>> {
+    let mut revwalk = repo.revwalk().context("get commit history")?;
+    revwalk
+        .set_sorting(git2::Sort::TIME)
+        .context("set revwalk sorting")?;
+    revwalk.push(from.id()).context("push ref to revwalk")?;
+
+    Ok(revwalk.map(move |oid| {
+        let oid = oid.context("walk commit history")?;
+        Ok(repo.find_commit(oid).context("find commit")?)
+    }))
+}
+
+/// When the author made the commit.
+pub fn authored_at(commit: &Commit<'_>) -> DateTime<Utc> {
+    DateTime::from_timestamp(commit.author().when().seconds(), 0).unwrap_or_default()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use git2::{Signature, Time};
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Write the files and commit them on `HEAD`.
+    pub(crate) fn commit(repo: &Repository, files: &[(&str, &str)], author: &str, seconds: i64) {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            let file = workdir.join(path);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::new(author, "elf@northpole", &Time::new(seconds, 0)).unwrap();
+        let parent = repo.head().ok().map(|h| h.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "commit",
+            &tree,
+            parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap();
+    }
+
+    /// A tar of a repository with one commit, plus `extra` files.
+    fn uploaded_tar(extra: &[(&str, &str)]) -> Vec<u8> {
+        let dir = TempDir::new().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit(&repo, &[("santa.txt", "COOKIE")], "elf", 0);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_dir_all(".", dir.path()).unwrap();
+        for (path, content) in extra {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_unpack_repository() {
+        let uploaded =
+            UploadedRepo::unpack(&uploaded_tar(&[])[..], &ArchiveLimits::default()).unwrap();
+        let head = resolve(uploaded.repo(), "HEAD").unwrap();
+        assert_eq!(head.author().name(), Some("elf"));
+    }
+
+    #[rstest]
+    #[case::alternates(".git/objects/info/alternates")]
+    #[case::commondir(".git/commondir")]
+    fn test_unpack_refuses_redirects(#[case] redirect: &str) {
+        let server = TempDir::new().unwrap();
+        Repository::init(server.path()).unwrap();
+        let target = server.path().join(".git").display().to_string();
+
+        let tar = uploaded_tar(&[(redirect, &target)]);
+        assert!(matches!(
+            UploadedRepo::unpack(&tar[..], &ArchiveLimits::default()),
+            Err(AppError::UnprocessableEntity(message)) if message.contains(redirect)
+        ));
+    }
+
+    #[test]
+    fn test_unpack_refuses_gitfile() {
+        let server = TempDir::new().unwrap();
+        let repo = Repository::init(server.path()).unwrap();
+        commit(&repo, &[("secret.txt", "hunter2")], "grinch", 0);
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let gitfile = f!("gitdir: {}", server.path().join(".git").display());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(gitfile.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, ".git", gitfile.as_bytes())
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        assert!(matches!(
+            UploadedRepo::unpack(&tar[..], &ArchiveLimits::default()),
+            Err(AppError::BadRequest(_))
+        ));
+    }
+}
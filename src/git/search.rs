@@ -0,0 +1,181 @@
+use std::{collections::HashMap, rc::Rc};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use git2::{Commit, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use glob::{MatchOptions, Pattern};
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+
+use crate::prelude::*;
+
+/// Longest accepted regex, once compiled.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+const GLOB_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A line of a file matching the search, in a commit.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchMatch {
+    pub commit: String,
+    pub author: String,
+    pub time: DateTime<Utc>,
+    pub path: String,
+    /// Starting at 1.
+    pub line_number: usize,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SearchResult {
+    pub matches: Vec<SearchMatch>,
+    pub commits_searched: usize,
+    /// Whether the search stopped at the limit.
+    pub truncated: bool,
+}
+
+/// Lines matching a regex in the files matching a glob, looked up in the
+/// commit trees without checking anything out.
+#[derive(Debug, Clone)]
+pub struct Search {
+    path: Option<Pattern>,
+    pattern: Regex,
+    limit: usize,
+}
+
+impl Search {
+    /// `path` is matched against the whole path, `**/` for any directory.
+    pub fn new(path: Option<&str>, pattern: &str, limit: usize) -> Result<Self> {
+        let path = path
+            .map(Pattern::new)
+            .transpose()
+            .map_err(|e| AppError::BadRequest(f!("invalid path glob: {e}")))?;
+        let pattern = RegexBuilder::new(pattern)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()
+            .map_err(|e| AppError::BadRequest(f!("invalid pattern: {e}")))?;
+
+        Ok(Self {
+            path,
+            pattern,
+            limit,
+        })
+    }
+
+    /// Search the commits reachable from `from`, newest first.
+    pub fn run(&self, repo: &Repository, from: &Commit<'_>) -> Result<SearchResult> {
+        let mut result = SearchResult {
+            matches: Vec::new(),
+            commits_searched: 0,
+            truncated: false,
+        };
+        // files are mostly unchanged between commits
+        let mut blobs = HashMap::new();
+        for commit in super::history(repo, from)? {
+            let commit = commit?;
+            result.commits_searched += 1;
+            for (path, oid) in self.files(&commit)? {
+                let lines = match blobs.get(&oid) {
+                    Some(lines) => Rc::clone(lines),
+                    None => {
+                        let lines = Rc::new(self.matching_lines(repo, oid)?);
+                        blobs.insert(oid, lines.clone());
+                        lines
+                    }
+                };
+                for (line_number, line) in lines.iter() {
+                    if result.matches.len() == self.limit {
+                        result.truncated = true;
+                        return Ok(result);
+                    }
+                    result.matches.push(SearchMatch {
+                        commit: commit.id().to_string(),
+                        author: String::from_utf8_lossy(commit.author().name_bytes()).into_owned(),
+                        time: super::authored_at(&commit),
+                        path: path.clone(),
+                        line_number: *line_number,
+                        line: line.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Files of the commit whose path matches the glob.
+    fn files(&self, commit: &Commit<'_>) -> Result<Vec<(String, Oid)>> {
+        let tree = commit.tree().context("get commit tree")?;
+        let mut files = Vec::new();
+        tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            if entry.kind() == Some(ObjectType::Blob) {
+                let path = f!("{dir}{}", String::from_utf8_lossy(entry.name_bytes()));
+                if self
+                    .path
+                    .as_ref()
+                    .is_none_or(|glob| glob.matches_with(&path, GLOB_OPTIONS))
+                {
+                    files.push((path, entry.id()));
+                }
+            }
+            TreeWalkResult::Ok
+        })
+        .context("walk commit tree")?;
+
+        Ok(files)
+    }
+
+    fn matching_lines(&self, repo: &Repository, oid: Oid) -> Result<Vec<(usize, String)>> {
+        let blob = repo.find_blob(oid).context("find blob")?;
+        if blob.is_binary() {
+            return Ok(Vec::new());
+        }
+
+        Ok(String::from_utf8_lossy(blob.content())
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| self.pattern.is_match(line))
+            .map(|(i, line)| (i + 1, line.to_string()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::tests::commit;
+
+    #[test]
+    fn test_search_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        commit(&repo, &[("a/santa.txt", "milk\n")], "Alice", 1_000);
+        commit(&repo, &[("a/santa.txt", "milk\nCOOKIE\n")], "Bob", 2_000);
+        commit(&repo, &[("notes.txt", "COOKIE")], "Carol", 3_000);
+
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        let search = Search::new(Some("**/santa.txt"), "COO?KIE", 10).unwrap();
+        let result = search.run(&repo, &head).unwrap();
+        assert_eq!(result.commits_searched, 3);
+        assert!(!result.truncated);
+        assert_eq!(
+            result
+                .matches
+                .iter()
+                .map(|m| (m.author.as_str(), m.path.as_str(), m.line_number))
+                .collect::<Vec<_>>(),
+            vec![("Carol", "a/santa.txt", 2), ("Bob", "a/santa.txt", 2)]
+        );
+
+        let result = Search::new(None, "COOKIE", 1)
+            .unwrap()
+            .run(&repo, &head)
+            .unwrap();
+        assert!(result.truncated);
+        assert_eq!(result.matches[0].path, "a/santa.txt");
+    }
+}
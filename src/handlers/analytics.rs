@@ -0,0 +1,283 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    prelude::*,
+    repo::{DynOrderRepository, DynRegionRepository, PercentileGroup, TimeBucket},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+/// `?format=json|csv`; without it, `Accept: text/csv` selects CSV.
+#[derive(Debug, Default, Deserialize)]
+pub struct FormatQuery {
+    format: Option<ReportFormat>,
+}
+
+impl FormatQuery {
+    fn resolve(&self, headers: &HeaderMap) -> ReportFormat {
+        if let Some(format) = self.format {
+            return format;
+        }
+
+        let accepts_csv = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/csv"));
+        if accepts_csv {
+            ReportFormat::Csv
+        } else {
+            ReportFormat::Json
+        }
+    }
+}
+
+/// Render rows as a JSON array or as a CSV attachment named `{name}.csv`.
+fn report<T: Serialize>(name: &str, rows: Vec<T>, format: ReportFormat) -> Result<Response> {
+    match format {
+        ReportFormat::Json => Ok(Json(rows).into_response()),
+        ReportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(Vec::new());
+            for row in rows {
+                writer
+                    .serialize(row)
+                    .map_err(|e| AppError::Internal(e.into()))?;
+            }
+            let body = writer
+                .into_inner()
+                .map_err(|e| AppError::Internal(anyhow::anyhow!("flush csv: {e}")))?;
+
+            Ok((
+                [
+                    (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        f!("attachment; filename=\"{name}.csv\""),
+                    ),
+                ],
+                body,
+            )
+                .into_response())
+        }
+    }
+}
+
+pub async fn get_gift_ranking(
+    State(orders): State<DynOrderRepository>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let ranking = orders.gift_ranking().await?;
+    report("gifts", ranking, format.resolve(&headers))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopGiftsQuery {
+    #[serde(default = "default_top_gifts")]
+    n: i64,
+}
+
+fn default_top_gifts() -> i64 {
+    3
+}
+
+/// The `n` most ordered gifts; gifts tied with the last one are included too.
+pub async fn get_top_gifts(
+    State(orders): State<DynOrderRepository>,
+    Query(query): Query<TopGiftsQuery>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    if query.n < 1 {
+        return Err(AppError::BadRequest(f!(
+            "n must be a positive number, got {}",
+            query.n
+        )));
+    }
+
+    let top = orders
+        .gift_ranking()
+        .await?
+        .into_iter()
+        .take_while(|gift| gift.rank <= query.n)
+        .collect::<Vec<_>>();
+    report("top_gifts", top, format.resolve(&headers))
+}
+
+pub async fn get_region_shares(
+    State(regions): State<DynRegionRepository>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let shares = regions.region_shares().await?;
+    report("regions", shares, format.resolve(&headers))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TimeSeriesQuery {
+    bucket: TimeBucket,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+
+pub async fn get_orders_over_time(
+    State(orders): State<DynOrderRepository>,
+    Query(query): Query<TimeSeriesQuery>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let series = orders
+        .orders_over_time(query.bucket, query.from, query.to)
+        .await?;
+    report(
+        f!("orders_per_{}", query.bucket.as_str()).as_str(),
+        series,
+        format.resolve(&headers),
+    )
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PercentilesQuery {
+    #[serde(default)]
+    by: PercentileGroup,
+}
+
+/// Median, 90th and 99th percentiles of the order quantities per gift or region.
+pub async fn get_quantity_percentiles(
+    State(orders): State<DynOrderRepository>,
+    Query(query): Query<PercentilesQuery>,
+    Query(format): Query<FormatQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let percentiles = orders.quantity_percentiles(query.by).await?;
+    report("percentiles", percentiles, format.resolve(&headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::body::to_bytes;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::repo::{ConflictPolicy, InMemoryRepository, Order};
+
+    async fn seeded_repository() -> DynOrderRepository {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+        let orders = [
+            (1, "Toy Train", 5, 9),
+            (2, "Doll", 3, 9),
+            (3, "Ball", 2, 10),
+            (4, "Doll", 2, 33),
+            (5, "Lego", 1, 34),
+        ]
+        .into_iter()
+        .map(|(id, gift_name, quantity, hour)| Order {
+            id,
+            region_id: 1,
+            gift_name: gift_name.to_string(),
+            quantity,
+            created_at: Some(
+                Utc.with_ymd_and_hms(2023, 12, 1, 0, 0, 0).unwrap() + chrono::Duration::hours(hour),
+            ),
+        })
+        .collect();
+        repo.insert_many(orders, ConflictPolicy::Error)
+            .await
+            .unwrap();
+
+        repo
+    }
+
+    async fn body_string(response: Response) -> String {
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_top_gifts_include_ties() {
+        let repo = seeded_repository().await;
+
+        let response = get_top_gifts(
+            State(repo),
+            Query(TopGiftsQuery { n: 1 }),
+            Query(FormatQuery::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = serde_json::from_str::<serde_json::Value>(&body_string(response).await).unwrap();
+
+        assert_eq!(
+            body,
+            serde_json::json!([
+                { "gift_name": "Doll", "quantity": 5, "rank": 1 },
+                { "gift_name": "Toy Train", "quantity": 5, "rank": 1 },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_quantity_percentiles() {
+        let repo = seeded_repository().await;
+
+        let response = get_quantity_percentiles(
+            State(repo),
+            Query(PercentilesQuery::default()),
+            Query(FormatQuery::default()),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        let body = serde_json::from_str::<serde_json::Value>(&body_string(response).await).unwrap();
+
+        assert_eq!(
+            body[1],
+            serde_json::json!({ "group": "Doll", "orders": 2, "p50": 2.5, "p90": 2.9, "p99": 2.99 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_orders_over_time_as_csv() {
+        let repo = seeded_repository().await;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, "text/csv".parse().unwrap());
+
+        let response = get_orders_over_time(
+            State(repo),
+            Query(TimeSeriesQuery {
+                bucket: TimeBucket::Day,
+                from: None,
+                to: None,
+            }),
+            Query(FormatQuery::default()),
+            headers,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/csv; charset=utf-8"
+        );
+        assert_eq!(
+            body_string(response).await,
+            "period,orders,quantity
+2023-12-01T00:00:00Z,3,10
+2023-12-02T00:00:00Z,2,3
+"
+        );
+    }
+}
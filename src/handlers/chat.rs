@@ -0,0 +1,392 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    Json,
+};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use ulid::Ulid;
+
+use crate::{
+    auth::{Authorized, ChatWrite},
+    chatroom::{
+        moderation::Moderation, views::ViewCounts, ChatConfig, ChatRooms, ChatroomMessage,
+        RoomEvent, RoomMembership, HISTORY_PAGE_SIZE,
+    },
+    prelude::*,
+    repo::{ChatMessage, DynChatRepository, MessageFilter},
+    ws::WsShutdown,
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct ListMessagesQuery {
+    limit: Option<i64>,
+    /// Id of the last message of the previous page.
+    cursor: Option<Ulid>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesPage {
+    messages: Vec<ChatMessage>,
+    next_cursor: Option<Ulid>,
+}
+
+/// Messages of a room, oldest first.
+pub async fn list_room_messages(
+    State(chat): State<DynChatRepository>,
+    Path(room_id): Path<u64>,
+    Query(query): Query<ListMessagesQuery>,
+) -> Result<Json<MessagesPage>> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::BadRequest(f!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}, got {limit}"
+        )));
+    }
+
+    // fetch one extra message to know whether there is a next page
+    let filter = MessageFilter {
+        after: query.cursor,
+        limit: limit + 1,
+    };
+    let mut messages = chat.messages(room_id, filter).await?;
+
+    let next_cursor = if messages.len() as i64 > limit {
+        messages.truncate(limit as usize);
+        messages.last().map(|m| m.id)
+    } else {
+        None
+    };
+
+    Ok(Json(MessagesPage {
+        messages,
+        next_cursor,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PostMessage {
+    message: String,
+}
+
+/// Send a message to a room without a WebSocket, as the owner of the API key.
+pub async fn post_room_message(
+    Authorized { principal, .. }: Authorized<ChatWrite>,
+    State(chat): State<DynChatRepository>,
+    State(chatrooms): State<Arc<ChatRooms>>,
+    State(config): State<Arc<ChatConfig>>,
+    State(moderation): State<Arc<Moderation>>,
+    Path(room_id): Path<u64>,
+    Json(post): Json<PostMessage>,
+) -> Result<(StatusCode, Json<ChatMessage>)> {
+    let user = principal.name;
+    config
+        .check_message(&post.message)
+        .map_err(|e| AppError::BadRequest(e.message))?;
+    if chatrooms.is_muted(room_id, &user) {
+        return Err(AppError::Forbidden(f!("{user} is muted in room {room_id}")));
+    }
+    if !chatrooms.try_acquire_post(room_id, &user) {
+        return Err(AppError::TooManyRequests(f!(
+            "sending too fast to room {room_id}, try again later"
+        )));
+    }
+    // escaping can make the message longer than was allowed
+    let text = moderation
+        .apply(post.message)
+        .and_then(|text| config.check_message(&text).map(|()| text))
+        .map_err(|e| AppError::BadRequest(e.message))?;
+
+    let message = ChatMessage::new(room_id, user, text);
+    chat.insert_message(&message).await?;
+    if let Some(room) = chatrooms.get(room_id) {
+        room.send(RoomEvent::Message(message.clone().into()));
+    }
+
+    Ok((StatusCode::CREATED, Json(message)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RoomEventsQuery {
+    /// Name to join the room under.
+    user: String,
+    /// Replay the messages sent after this one, like the `Last-Event-ID` header.
+    since: Option<Ulid>,
+    /// Also receive the `join`, `leave` and `typing` events of the room.
+    #[serde(default)]
+    presence: bool,
+}
+
+/// Stream a room as Server-Sent Events, for clients that cannot use WebSockets.
+///
+/// The stream joins the room like a WebSocket session does; messages are sent
+/// as `message` events with the message id as event id.
+pub async fn room_events(
+    State(chat): State<DynChatRepository>,
+    State(chatrooms): State<Arc<ChatRooms>>,
+    State(views): State<Arc<ViewCounts>>,
+    State(shutdown): State<WsShutdown>,
+    Path(room_id): Path<u64>,
+    Query(query): Query<RoomEventsQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, axum::Error>>>> {
+    let since = match headers.get("last-event-id") {
+        Some(id) => Some(
+            id.to_str()
+                .ok()
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| AppError::BadRequest("invalid Last-Event-ID".to_string()))?,
+        ),
+        None => query.since,
+    };
+    let membership = chatrooms.join(room_id, &query.user)?;
+    let kicked = membership.kicked();
+    tracing::debug!("Room {}: User {}: Streaming events", room_id, query.user);
+
+    let events = RoomEventStream {
+        rx: membership.room().subscribe(),
+        membership,
+        chat,
+        views,
+        presence: query.presence,
+        replay_after: since,
+        replayed: VecDeque::new(),
+        replayed_ids: HashSet::new(),
+    };
+    let stream = futures::stream::unfold(events, |mut events| async move {
+        let event = events.next_event().await?;
+        Some((event, events))
+    })
+    .take_until(async move {
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            _ = kicked.cancelled() => {}
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// Events of a room for one SSE subscriber, leaving the room when dropped.
+struct RoomEventStream {
+    membership: RoomMembership,
+    rx: broadcast::Receiver<RoomEvent>,
+    chat: DynChatRepository,
+    views: Arc<ViewCounts>,
+    presence: bool,
+    /// Stored messages after this one are still to be replayed.
+    replay_after: Option<Ulid>,
+    replayed: VecDeque<ChatMessage>,
+    /// Messages already sent, which may also be received live.
+    replayed_ids: HashSet<Ulid>,
+}
+
+impl RoomEventStream {
+    async fn next_event(&mut self) -> Option<std::result::Result<Event, axum::Error>> {
+        let room = self.membership.room().clone();
+        loop {
+            if let Some(message) = self.replayed.pop_front() {
+                self.replayed_ids.insert(message.id);
+                return Some(self.message_event(message.into()));
+            }
+            if let Some(after) = self.replay_after {
+                let filter = MessageFilter {
+                    after: Some(after),
+                    limit: HISTORY_PAGE_SIZE,
+                };
+                let page = match self.chat.messages(room.id(), filter).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        tracing::error!(
+                            "Room {}: User {}: Failed to replay history: {:?}",
+                            room.id(),
+                            self.membership.user(),
+                            e
+                        );
+                        return None;
+                    }
+                };
+                self.replay_after = match page.last() {
+                    Some(last) if page.len() as i64 == HISTORY_PAGE_SIZE => Some(last.id),
+                    _ => None,
+                };
+                self.replayed.extend(page);
+                continue;
+            }
+
+            let event = match self.rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(count)) => {
+                    room.record_dropped(count);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            match event {
+                RoomEvent::Message(message) => {
+                    // already sent while replaying the history
+                    if self.replayed_ids.remove(&message.id) {
+                        continue;
+                    }
+                    return Some(self.message_event(message));
+                }
+                RoomEvent::Join { ref user }
+                | RoomEvent::Leave { ref user }
+                | RoomEvent::Typing { ref user }
+                    if self.presence && user != self.membership.user() =>
+                {
+                    let name = match event {
+                        RoomEvent::Join { .. } => "join",
+                        RoomEvent::Leave { .. } => "leave",
+                        _ => "typing",
+                    };
+                    return Some(Event::default().event(name).json_data(&event));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// A `message` event with the same payload as the WebSocket, counted as viewed.
+    fn message_event(&self, message: ChatroomMessage) -> std::result::Result<Event, axum::Error> {
+        let room_id = self.membership.room().id();
+        self.views.record(room_id, &message.body.user);
+        Event::default()
+            .event("message")
+            .id(message.id.to_string())
+            .json_data(&message.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        auth::{Principal, Scope},
+        chatroom::{moderation::EscapeHtml, rate_limit::RateLimit, views::ViewFilter},
+        repo::InMemoryRepository,
+    };
+
+    #[tokio::test]
+    async fn test_list_room_messages_paginated() {
+        let chat: DynChatRepository = Arc::new(InMemoryRepository::new());
+        for (room, message) in [(1, "hi"), (2, "elsewhere"), (1, "ho"), (1, "ho ho")] {
+            let message = ChatMessage::new(room, "santa".to_string(), message.to_string());
+            chat.insert_message(&message).await.unwrap();
+        }
+
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let Json(page) = list_room_messages(
+                State(chat.clone()),
+                Path(1),
+                Query(ListMessagesQuery {
+                    limit: Some(2),
+                    cursor,
+                }),
+            )
+            .await
+            .unwrap();
+            pages.push(
+                page.messages
+                    .iter()
+                    .map(|m| m.message.clone())
+                    .collect::<Vec<_>>(),
+            );
+            match page.next_cursor {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(pages, vec![vec!["hi", "ho"], vec!["ho ho"]]);
+    }
+
+    #[tokio::test]
+    async fn test_post_room_message_reaches_members() {
+        let chat: DynChatRepository = Arc::new(InMemoryRepository::new());
+        let config = Arc::new(ChatConfig {
+            user_rate_limit: Some(RateLimit::new(3, 0.0)),
+            ..Default::default()
+        });
+        let chatrooms = Arc::new(ChatRooms::new(&config));
+        let moderation = Arc::new(Moderation::new().with(EscapeHtml));
+        let santa = Principal {
+            name: "santa".to_string(),
+            scopes: vec![Scope::ChatWrite],
+        };
+        let post = |message: &str| {
+            post_room_message(
+                Authorized::new(santa.clone()),
+                State(chat.clone()),
+                State(chatrooms.clone()),
+                State(config.clone()),
+                State(moderation.clone()),
+                Path(1),
+                Json(PostMessage {
+                    message: message.to_string(),
+                }),
+            )
+        };
+
+        let alice = chatrooms.join(1, "alice").unwrap();
+        let views = Arc::new(ViewCounts::default());
+        let mut events = RoomEventStream {
+            rx: alice.room().subscribe(),
+            membership: alice,
+            chat: chat.clone(),
+            views: views.clone(),
+            presence: false,
+            replay_after: None,
+            replayed: VecDeque::new(),
+            replayed_ids: HashSet::new(),
+        };
+
+        let (status, Json(posted)) = post("ho <b>ho</b>").await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(posted.message, "ho &lt;b&gt;ho&lt;/b&gt;");
+        assert!(events.next_event().await.unwrap().is_ok());
+        assert_eq!(views.total(&ViewFilter::default()), 1);
+        assert!(matches!(
+            post(&"🎁".repeat(129)).await,
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(matches!(
+            post(&"<".repeat(100)).await,
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(post("ho").await.is_ok());
+        assert!(matches!(
+            post("ho").await,
+            Err(AppError::TooManyRequests(_))
+        ));
+        chatrooms.mute(1, "santa", None).unwrap();
+        assert!(matches!(post("ho").await, Err(AppError::Forbidden(_))));
+
+        let stored = chat
+            .messages(
+                1,
+                MessageFilter {
+                    after: None,
+                    limit: 10,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[0].id, posted.id);
+    }
+}
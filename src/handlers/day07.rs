@@ -43,7 +43,7 @@ pub async fn bake_cookies(
             }
         }
 
-        count.min(u64::MAX)
+        count
     });
 
     pantry.iter_mut().for_each(|(key, value)| {
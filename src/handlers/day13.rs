@@ -1,12 +1,16 @@
-use anyhow::Context;
 use axum::{
-    extract::{self, State},
+    extract::{self, Query, State},
     Json,
 };
+use serde::Deserialize;
 use serde_json::json;
-use sqlx::{Postgres, QueryBuilder};
 
-use crate::{app_state::AppState, prelude::*, repo::Order};
+use crate::{
+    app_state::AppState,
+    auth::{AdminReset, Authorized, OrdersWrite},
+    prelude::*,
+    repo::{ConflictPolicy, DynOrderRepository, InsertReport, Order},
+};
 
 pub async fn db_health_check(State(state): State<AppState>) -> Result<String> {
     let (r,) = sqlx::query_as::<_, (i32,)>("SELECT 20231213")
@@ -15,74 +19,166 @@ pub async fn db_health_check(State(state): State<AppState>) -> Result<String> {
     Ok(r.to_string())
 }
 
-pub async fn reset_orders_db(State(state): State<AppState>) -> Result<()> {
-    let mut transaction = state.db.begin().await.context("start transaction")?;
+pub async fn reset_orders_db(
+    _: Authorized<AdminReset>,
+    State(orders): State<DynOrderRepository>,
+) -> Result<()> {
+    orders.reset().await
+}
 
-    sqlx::query("DROP TABLE IF EXISTS orders")
-        .execute(&mut *transaction)
-        .await
-        .context("drop table")?;
-
-    sqlx::query(
-        "
-CREATE TABLE orders (
-  id INT PRIMARY KEY,
-  region_id INT,
-  gift_name VARCHAR(50),
-  quantity INT
-)",
-    )
-    .execute(&mut *transaction)
-    .await
-    .context("create table")?;
-
-    transaction.commit().await.context("commit transaction")?;
-
-    Ok(())
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateOrdersQuery {
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
 }
 
 pub async fn create_orders(
-    State(state): State<AppState>,
+    _: Authorized<OrdersWrite>,
+    State(repo): State<DynOrderRepository>,
+    Query(query): Query<CreateOrdersQuery>,
     extract::Json(orders): extract::Json<Vec<Order>>,
-) -> Result<()> {
+) -> Result<Json<InsertReport>> {
     tracing::debug!("Creating orders: {:?}", orders);
 
-    if orders.is_empty() {
-        return Ok(());
+    for order in &orders {
+        order.validate()?;
     }
+    let report = repo.insert_many(orders, query.on_conflict).await?;
+    tracing::debug!("Created orders: {:?}", report);
 
-    let mut query_builder =
-        QueryBuilder::<Postgres>::new("INSERT INTO orders (id, region_id, gift_name, quantity)");
+    Ok(Json(report))
+}
 
-    query_builder.push_values(orders, |mut b, order| {
-        b.push_bind(order.id);
-        b.push_bind(order.region_id);
-        b.push_bind(order.gift_name);
-        b.push_bind(order.quantity);
-    });
+pub async fn get_total_orders(
+    State(orders): State<DynOrderRepository>,
+) -> Result<Json<serde_json::Value>> {
+    let total = orders.total_quantity().await?;
+    Ok(Json(json!({ "total": total })))
+}
 
-    tracing::debug!("Query: {}", query_builder.sql());
+pub async fn get_popular_gift(
+    State(orders): State<DynOrderRepository>,
+) -> Result<Json<serde_json::Value>> {
+    let popular = orders.most_popular_gift().await?;
+    Ok(Json(json!({ "popular": popular })))
+}
 
-    let query = query_builder.build();
-    query.execute(&state.db).await?;
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
 
-    Ok(())
-}
+    use rstest::rstest;
 
-pub async fn get_total_orders(State(state): State<AppState>) -> Result<Json<serde_json::Value>> {
-    let (r,) = sqlx::query_as::<_, (i64,)>("SELECT SUM(quantity) FROM orders")
-        .fetch_one(&state.db)
-        .await?;
-    Ok(Json(json!({ "total": r })))
-}
+    use super::*;
+    use crate::{auth::Principal, repo::InMemoryRepository};
 
-pub async fn get_popular_gift(State(state): State<AppState>) -> Result<Json<serde_json::Value>> {
-    let result = sqlx::query_as::<_, (String,)>("SELECT gift_name, SUM(quantity) AS quantity FROM orders GROUP BY gift_name ORDER BY quantity DESC LIMIT 1")
-        .fetch_optional(&state.db)
-        .await?;
-    if let Some((name,)) = result {
-        Ok(Json(json!({ "popular": name })))
-    } else {
-        Ok(Json(json!({ "popular": null })))
+    fn order(id: i32, gift_name: &str, quantity: i32) -> Order {
+        Order {
+            id,
+            region_id: 1,
+            gift_name: gift_name.to_string(),
+            quantity,
+            created_at: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_total_and_popular_gift() {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+
+        let Json(popular) = get_popular_gift(State(repo.clone())).await.unwrap();
+        assert_eq!(popular, json!({ "popular": null }));
+
+        repo.insert_many(
+            vec![
+                order(1, "Toy Train", 5),
+                order(2, "Doll", 8),
+                order(3, "Toy Train", 4),
+            ],
+            ConflictPolicy::Error,
+        )
+        .await
+        .unwrap();
+
+        let Json(total) = get_total_orders(State(repo.clone())).await.unwrap();
+        assert_eq!(total, json!({ "total": 17 }));
+
+        let Json(popular) = get_popular_gift(State(repo)).await.unwrap();
+        assert_eq!(popular, json!({ "popular": "Toy Train" }));
+    }
+
+    #[rstest]
+    #[case(ConflictPolicy::Skip, vec![3], vec![], vec![1, 2], 8)]
+    #[case(ConflictPolicy::Update, vec![3], vec![1, 2], vec![], 12)]
+    #[tokio::test]
+    async fn test_create_orders_on_conflict(
+        #[case] on_conflict: ConflictPolicy,
+        #[case] inserted: Vec<i32>,
+        #[case] updated: Vec<i32>,
+        #[case] skipped: Vec<i32>,
+        #[case] total: i64,
+    ) {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+        let existing = vec![order(1, "Doll", 1), order(2, "Doll", 2)];
+        repo.insert_many(existing, ConflictPolicy::Error)
+            .await
+            .unwrap();
+
+        let batch = vec![
+            order(1, "Ball", 3),
+            order(2, "Ball", 4),
+            order(3, "Ball", 5),
+        ];
+        let Json(report) = create_orders(
+            Authorized::new(Principal::unrestricted()),
+            State(repo.clone()),
+            Query(CreateOrdersQuery { on_conflict }),
+            extract::Json(batch),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            report,
+            InsertReport {
+                inserted,
+                updated,
+                skipped,
+            }
+        );
+        assert_eq!(repo.total_quantity().await.unwrap(), total);
+    }
+
+    #[tokio::test]
+    async fn test_create_orders_rejects_conflicts_and_invalid_orders() {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+        let create = |orders| {
+            create_orders(
+                Authorized::new(Principal::unrestricted()),
+                State(repo.clone()),
+                Query(CreateOrdersQuery::default()),
+                extract::Json(orders),
+            )
+        };
+
+        let Json(report) = create(vec![order(1, "Doll", 1)]).await.unwrap();
+        assert_eq!(report.inserted, vec![1]);
+        assert!(matches!(
+            create(vec![order(1, "Doll", 1)]).await,
+            Err(AppError::Conflict(_))
+        ));
+        assert!(matches!(
+            create(vec![order(2, "Doll", 1), order(2, "Doll", 1)]).await,
+            Err(AppError::Conflict(_))
+        ));
+        assert!(matches!(
+            create(vec![order(3, "Doll", -1)]).await,
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(matches!(
+            create(vec![order(4, &"🎁".repeat(51), 1)]).await,
+            Err(AppError::BadRequest(_))
+        ));
+        assert_eq!(repo.total_quantity().await.unwrap(), 1);
     }
 }
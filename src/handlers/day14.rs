@@ -1,4 +1,4 @@
-use crate::prelude::*;
+use crate::{prelude::*, utils::escape_html};
 use axum::{extract, response::Html};
 use serde::Deserialize;
 
@@ -30,7 +30,7 @@ pub async fn render_safe_html(
 ) -> Html<String> {
     tracing::debug!("render_unsafe_html: {:?}", payload);
 
-    let encoded_content = html_escape::encode_double_quoted_attribute(payload.content.as_str());
+    let encoded_content = escape_html(&payload.content);
 
     Html(f!(
         "<html>
@@ -12,14 +12,13 @@ pub struct RequestInput {
 
 pub async fn password_validator(
     extract::Json(payload): extract::Json<RequestInput>,
-) -> Result<Json<serde_json::Value>> {
+) -> (StatusCode, Json<serde_json::Value>) {
     tracing::debug!("password_validator: {:?}", payload);
 
     if is_valid_password(payload.input.as_str()) {
-        Ok(Json(json!({"result": "nice"})))
+        (StatusCode::OK, Json(json!({"result": "nice"})))
     } else {
-        let msg = json!({"result": "naughty"});
-        Err(AppError::BadRequest(msg.to_string()))
+        (StatusCode::BAD_REQUEST, Json(json!({"result": "naughty"})))
     }
 }
 
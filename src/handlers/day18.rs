@@ -1,149 +1,282 @@
-use std::collections::HashMap;
-
-use anyhow::Context;
-use axum::extract::{self, Path, State};
+use axum::extract::{self, Path, Query, State};
 use axum::Json;
-use serde::Serialize;
-use sqlx::prelude::FromRow;
-use sqlx::{Postgres, QueryBuilder};
+use base64::{engine::general_purpose, Engine};
+use serde::{Deserialize, Serialize};
 
-use crate::app_state::AppState;
+use crate::auth::{AdminReset, Authorized, RegionsWrite};
 use crate::prelude::*;
-use crate::repo::Region;
-
-pub async fn reset_orders_and_regions_db(State(state): State<AppState>) -> Result<()> {
-    let mut transaction = state.db.begin().await.context("start transaction")?;
-
-    sqlx::query("DROP TABLE IF EXISTS regions")
-        .execute(&mut *transaction)
-        .await
-        .context("drop regions table")?;
+use crate::repo::{
+    DynRegionRepository, Region, RegionCursor, RegionSort, RegionsOrdersSummary, RegionsTopGifts,
+    TopGiftsFilter,
+};
 
-    sqlx::query("DROP TABLE IF EXISTS orders")
-        .execute(&mut *transaction)
-        .await
-        .context("drop orders table")?;
-
-    sqlx::query(
-        r#"
-        
-        CREATE TABLE regions ( id INT PRIMARY KEY, name varchar(50))"#,
-    )
-    .execute(&mut *transaction)
-    .await
-    .context("create regions table")?;
-
-    sqlx::query(
-        r#"
-        CREATE TABLE orders ( id INT PRIMARY KEY, region_id INT, gift_name varchar(50), quantity INT
-)"#,
-    )
-    .execute(&mut *transaction)
-    .await
-    .context("create orders table")?;
-
-    transaction.commit().await.context("commit transaction")?;
-
-    Ok(())
+pub async fn reset_orders_and_regions_db(
+    _: Authorized<AdminReset>,
+    State(regions): State<DynRegionRepository>,
+) -> Result<()> {
+    regions.reset_with_orders().await
 }
 
 pub async fn create_regions(
-    State(state): State<AppState>,
+    _: Authorized<RegionsWrite>,
+    State(repo): State<DynRegionRepository>,
     extract::Json(regions): extract::Json<Vec<Region>>,
 ) -> Result<()> {
     tracing::debug!("Creating regions: {:?}", regions);
-
-    if regions.is_empty() {
-        return Ok(());
+    for region in &regions {
+        region.validate()?;
     }
 
-    let mut query_builder = QueryBuilder::<Postgres>::new("INSERT INTO regions (id, name)");
-    query_builder.push_values(regions, |mut b, region| {
-        b.push_bind(region.id);
-        b.push_bind(region.name);
-    });
-    tracing::debug!("Query: {}", query_builder.sql());
-
-    query_builder.build().execute(&state.db).await?;
-
-    Ok(())
+    repo.insert_many(regions).await
 }
 
-#[derive(Debug, Serialize, FromRow)]
-pub struct RegionsOrdersSummary {
-    region: String,
-    total: i64,
-}
 pub async fn get_regions_orders_summary(
-    State(state): State<AppState>,
+    State(regions): State<DynRegionRepository>,
 ) -> Result<Json<Vec<RegionsOrdersSummary>>> {
-    let data = sqlx::query_as::<_, RegionsOrdersSummary>(
-        r#"
-    SELECT regions.name AS region, SUM(orders.quantity) AS total 
-    FROM orders INNER JOIN regions ON orders.region_id = regions.id 
-    GROUP BY 1 
-    ORDER BY 1"#,
-    )
-    .fetch_all(&state.db)
-    .await?;
-
-    Ok(Json(data))
+    Ok(Json(regions.orders_summary().await?))
+}
+
+const MAX_REGIONS_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RegionsTopGiftsQuery {
+    /// Only regions whose name contains this, ignoring case.
+    region: Option<String>,
+    min_quantity: Option<i64>,
+    #[serde(default)]
+    sort: RegionSort,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    #[serde(default)]
+    include_quantity: bool,
 }
 
+/// A bare list of regions, or a page of them once `limit` is set.
 #[derive(Debug, Serialize)]
-pub struct RegionsTopGifts {
-    region: String,
-    top_gifts: Vec<String>,
+#[serde(untagged)]
+pub enum RegionsTopGiftsResponse {
+    All(Vec<RegionsTopGifts>),
+    Page {
+        regions: Vec<RegionsTopGifts>,
+        next_cursor: Option<String>,
+    },
 }
 
-pub async fn get_regions_top_gifts(
-    State(state): State<AppState>,
-    Path(number): Path<u32>,
-) -> Result<Json<Vec<RegionsTopGifts>>> {
-    let mut sql = "SELECT name, NULL FROM regions ORDER BY name";
-    if number > 0 {
-        sql = r#"
-WITH added_row_number AS (
-  SELECT 
-    regions.name AS region, 
-    orders.gift_name AS gift_name, 
-    ROW_NUMBER() OVER(PARTITION BY regions.name ORDER BY SUM(orders.quantity) DESC, orders.gift_name ASC) AS row_number
-  FROM orders FULL JOIN regions ON orders.region_id = regions.id
-  GROUP BY 1, 2
-  ORDER BY 1, 3 DESC
-)
-SELECT region, gift_name FROM added_row_number WHERE row_number <= $1 ORDER BY region, row_number;
-        "#;
-    }
+fn encode_cursor(cursor: &RegionCursor) -> Result<String> {
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor)?))
+}
 
-    let data = sqlx::query_as::<_, (Option<String>, Option<String>)>(sql)
-        .bind(number as i32)
-        .fetch_all(&state.db)
-        .await?;
-    tracing::debug!("Top gifts of Regions (raw data): {:?}", data);
+fn decode_cursor(cursor: &str) -> Result<RegionCursor> {
+    general_purpose::URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| AppError::BadRequest(f!("invalid cursor {cursor:?}")))
+}
 
-    let mut top_gifts = HashMap::<String, Vec<String>>::new();
-    for (region, gift) in data {
-        let Some(region) = region else {
-            continue;
-        };
-        let v = top_gifts.entry(region).or_default();
-        if let Some(gift) = gift {
-            v.push(gift);
+/// Regions with their `number` most ordered gifts.
+///
+/// Without query parameters every region is returned, sorted by name. When
+/// `limit` is set, the regions are returned as a page with the `next_cursor`
+/// of the following one, if any.
+pub async fn get_regions_top_gifts(
+    State(regions): State<DynRegionRepository>,
+    Path(number): Path<u32>,
+    Query(query): Query<RegionsTopGiftsQuery>,
+) -> Result<Json<RegionsTopGiftsResponse>> {
+    if let Some(limit) = query.limit {
+        if !(1..=MAX_REGIONS_PAGE_SIZE).contains(&limit) {
+            return Err(AppError::BadRequest(f!(
+                "limit must be between 1 and {MAX_REGIONS_PAGE_SIZE}, got {limit}"
+            )));
         }
     }
-    let mut result = top_gifts
-        .into_iter()
-        .map(|(r, g)| RegionsTopGifts {
-            region: r,
-            top_gifts: g,
-        })
-        .collect::<Vec<RegionsTopGifts>>();
-    result.sort_by(|a, b| a.region.cmp(&b.region));
+
+    // fetch one extra region to know whether there is a next page
+    let filter = TopGiftsFilter {
+        number,
+        region: query.region,
+        min_quantity: query.min_quantity,
+        sort: query.sort,
+        after: query.cursor.as_deref().map(decode_cursor).transpose()?,
+        limit: query.limit.map(|limit| limit + 1),
+    };
+    let mut result = regions.top_gifts(filter).await?;
     tracing::debug!(
         "Top gifts of Regions: {:?}",
         serde_json::to_string(&result)?
     );
 
-    Ok(Json(result))
+    let mut next_cursor = None;
+    if let Some(limit) = query.limit {
+        if result.len() as i64 > limit {
+            result.truncate(limit as usize);
+            next_cursor = result
+                .last()
+                .map(|last| encode_cursor(&last.cursor()))
+                .transpose()?;
+        }
+    }
+    if !query.include_quantity {
+        result = result
+            .into_iter()
+            .map(RegionsTopGifts::without_quantities)
+            .collect();
+    }
+
+    Ok(Json(match query.limit {
+        None => RegionsTopGiftsResponse::All(result),
+        Some(_) => RegionsTopGiftsResponse::Page {
+            regions: result,
+            next_cursor,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rstest::rstest;
+
+    use super::*;
+    use crate::repo::{
+        ConflictPolicy, InMemoryRepository, Order, OrderRepository, RegionRepository,
+    };
+
+    async fn seeded_repository() -> InMemoryRepository {
+        let repo = InMemoryRepository::new();
+        RegionRepository::insert_many(
+            &repo,
+            vec![
+                Region {
+                    id: 1,
+                    name: "North Pole".to_string(),
+                },
+                Region {
+                    id: 2,
+                    name: "Europe".to_string(),
+                },
+                Region {
+                    id: 3,
+                    name: "Antarctica".to_string(),
+                },
+            ],
+        )
+        .await
+        .unwrap();
+        OrderRepository::insert_many(
+            &repo,
+            [
+                (1, 1, "Toy Train", 5),
+                (2, 2, "Doll", 8),
+                (3, 1, "Doll", 2),
+                (4, 1, "Ball", 2),
+            ]
+            .into_iter()
+            .map(|(id, region_id, gift_name, quantity)| Order {
+                id,
+                region_id,
+                gift_name: gift_name.to_string(),
+                quantity,
+                created_at: None,
+            })
+            .collect(),
+            ConflictPolicy::Error,
+        )
+        .await
+        .unwrap();
+
+        repo
+    }
+
+    #[tokio::test]
+    async fn test_regions_orders_summary() {
+        let repo: DynRegionRepository = Arc::new(seeded_repository().await);
+
+        let Json(summary) = get_regions_orders_summary(State(repo)).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(summary).unwrap(),
+            serde_json::json!([
+                { "region": "Europe", "total": 8 },
+                { "region": "North Pole", "total": 9 },
+            ])
+        );
+    }
+
+    #[rstest]
+    #[case(0, vec![vec![], vec![], vec![]])]
+    #[case(1, vec![vec![], vec!["Doll"], vec!["Toy Train"]])]
+    #[case(3, vec![vec![], vec!["Doll"], vec!["Toy Train", "Ball", "Doll"]])]
+    #[tokio::test]
+    async fn test_regions_top_gifts(#[case] number: u32, #[case] expected: Vec<Vec<&str>>) {
+        let repo: DynRegionRepository = Arc::new(seeded_repository().await);
+
+        let Json(RegionsTopGiftsResponse::All(top_gifts)) = get_regions_top_gifts(
+            State(repo),
+            Path(number),
+            Query(RegionsTopGiftsQuery::default()),
+        )
+        .await
+        .unwrap() else {
+            panic!("expected every region");
+        };
+        let regions = top_gifts
+            .iter()
+            .map(|r| r.region.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(regions, vec!["Antarctica", "Europe", "North Pole"]);
+        let gifts = top_gifts.iter().map(|r| &r.top_gifts).collect::<Vec<_>>();
+        assert_eq!(serde_json::json!(gifts), serde_json::json!(expected));
+    }
+
+    #[tokio::test]
+    async fn test_regions_top_gifts_paginated_by_total() {
+        let repo: DynRegionRepository = Arc::new(seeded_repository().await);
+        let mut query = RegionsTopGiftsQuery {
+            min_quantity: Some(3),
+            sort: RegionSort::Total,
+            limit: Some(1),
+            include_quantity: true,
+            ..Default::default()
+        };
+
+        let mut pages = vec![];
+        loop {
+            let Json(RegionsTopGiftsResponse::Page {
+                regions,
+                next_cursor,
+            }) = get_regions_top_gifts(State(repo.clone()), Path(2), Query(query.clone()))
+                .await
+                .unwrap()
+            else {
+                panic!("expected a page");
+            };
+            pages.push(serde_json::to_value(regions).unwrap());
+            let Some(cursor) = next_cursor else {
+                break;
+            };
+            query = RegionsTopGiftsQuery {
+                cursor: Some(cursor),
+                ..query
+            };
+        }
+
+        assert_eq!(
+            pages,
+            vec![
+                serde_json::json!([{
+                    "region": "North Pole",
+                    "total": 9,
+                    "top_gifts": [{ "gift_name": "Toy Train", "quantity": 5 }],
+                }]),
+                serde_json::json!([{
+                    "region": "Europe",
+                    "total": 8,
+                    "top_gifts": [{ "gift_name": "Doll", "quantity": 8 }],
+                }]),
+                serde_json::json!([{ "region": "Antarctica", "total": 0, "top_gifts": [] }]),
+            ]
+        );
+    }
 }
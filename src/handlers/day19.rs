@@ -1,34 +1,75 @@
-use std::sync::{
-    atomic::{self, AtomicU64},
-    Arc,
-};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
-        Path, State, WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Path, Query, State, WebSocketUpgrade,
     },
+    http::StatusCode,
     response::IntoResponse,
+    Json,
 };
 use futures::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use serde::Deserialize;
-use tokio::sync::broadcast;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+use tokio_util::sync::CancellationToken;
+use ulid::Ulid;
 
-use crate::app_state::{AppState, ChatroomMessage, ChatroomMessageBody};
+use crate::app_state::AppState;
+use crate::auth::{AdminReset, Authorized, ChatModerate};
+use crate::chatroom::{
+    protocol::{ClientFrame, ErrorCode, FrameError, Protocol, ServerFrame},
+    rate_limit::{Strikes, TokenBucket},
+    views::{ViewCounts, ViewFilter},
+    ChatRoom, ChatRooms, RoomEvent, RoomMetrics, HISTORY_PAGE_SIZE,
+};
 use crate::prelude::*;
+use crate::repo::{ChatMessage, DynChatRepository, MessageFilter};
+use crate::ws::{self, WsConfig, WsShutdown};
 
-pub async fn ws_handler(ws: WebSocketUpgrade) -> impl IntoResponse {
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(config): State<Arc<WsConfig>>,
+    State(shutdown): State<WsShutdown>,
+) -> impl IntoResponse {
     tracing::trace!("Serving websocket");
-    ws.on_upgrade(handle_socket)
+    ws.on_upgrade(move |socket| shutdown.track(handle_socket(socket, config, shutdown.clone())))
 }
 
-async fn handle_socket(mut socket: WebSocket) {
+async fn handle_socket(mut socket: WebSocket, config: Arc<WsConfig>, shutdown: WsShutdown) {
     let mut is_game_started = false;
+    let mut ping_timer = config.ping_timer();
+    let idle = tokio::time::sleep(config.idle_timeout);
+    tokio::pin!(idle);
+
+    loop {
+        let msg = tokio::select! {
+            msg = socket.recv() => msg,
+            _ = ping_timer.tick() => {
+                if let Err(e) = socket.send(Message::Ping(Vec::new())).await {
+                    tracing::error!("Error sending ping: {:?}", e);
+                    return;
+                }
+                continue;
+            }
+            _ = &mut idle => {
+                ws::send_close(&mut socket, config.idle_close()).await;
+                return;
+            }
+            _ = shutdown.cancelled() => {
+                ws::send_close(&mut socket, WsShutdown::close_frame()).await;
+                return;
+            }
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        idle.as_mut()
+            .reset(tokio::time::Instant::now() + config.idle_timeout);
 
-    while let Some(msg) = socket.recv().await {
         tracing::trace!("Received message: {:?}", msg);
         let Ok(msg) = msg else {
             tracing::error!("Error receiving message: {:?}", msg);
@@ -45,12 +86,10 @@ async fn handle_socket(mut socket: WebSocket) {
                 "serve" => {
                     is_game_started = true;
                 }
-                "ping" => {
-                    if is_game_started {
-                        let result = socket.send(Message::Text("pong".to_string())).await;
-                        if let Err(e) = result {
-                            tracing::error!("Error sending message: {:?}", e);
-                        }
+                "ping" if is_game_started => {
+                    let result = socket.send(Message::Text("pong".to_string())).await;
+                    if let Err(e) = result {
+                        tracing::error!("Error sending message: {:?}", e);
                     }
                 }
                 _ => {}
@@ -59,43 +98,94 @@ async fn handle_socket(mut socket: WebSocket) {
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ChatroomQuery {
+    /// Replay the messages sent after this one before the live ones.
+    since: Option<Ulid>,
+    /// Also receive the `join`, `leave` and `typing` events of the room.
+    #[serde(default)]
+    presence: bool,
+    /// Version of the tagged protocol, the legacy one is used without it.
+    v: Option<u8>,
+}
+
 pub async fn chatroom(
     Path((room_id, user)): Path<(u64, String)>,
+    Query(query): Query<ChatroomQuery>,
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse> {
     tracing::trace!("Serving chatroom {} for user {}", room_id, user);
+    let protocol = Protocol::from_version(query.v)?;
+
+    let shutdown = state.ws_shutdown.clone();
+    Ok(ws.on_upgrade(move |mut socket| {
+        shutdown.track(async move {
+            let membership = match state.chatrooms.join(room_id, &user) {
+                Ok(membership) => membership,
+                Err(e) => {
+                    tracing::debug!("Room {}: User {}: Rejected: {}", room_id, user, e);
+                    let close = CloseFrame {
+                        code: close_code::POLICY,
+                        reason: f!("user name {user} is already taken in room {room_id}").into(),
+                    };
+                    ws::send_close(&mut socket, close).await;
+                    return;
+                }
+            };
 
-    let tweet_counter = state.chatroom_counter;
-    let chatroom_broadcaster = state.chatroom_broadcaster;
+            let session = ChatSession {
+                room: membership.room().clone(),
+                user: membership.user().to_string(),
+                protocol,
+                presence: query.presence,
+                kicked: membership.kicked(),
+            };
+            handle_chatroom_socket(socket, session, state, query.since).await;
+            drop(membership);
+        })
+    }))
+}
 
-    ws.on_upgrade(move |socket| {
-        handle_chatroom_socket(socket, room_id, user, chatroom_broadcaster, tweet_counter)
-    })
+/// What the read task has the write task send.
+#[derive(Debug)]
+enum Reply {
+    Frame(ServerFrame),
+    Close(CloseFrame<'static>),
+}
+
+/// A member of a room and how it talks to the server.
+#[derive(Debug, Clone)]
+struct ChatSession {
+    room: Arc<ChatRoom>,
+    user: String,
+    protocol: Protocol,
+    presence: bool,
+    kicked: CancellationToken,
 }
 
 async fn handle_chatroom_socket(
     socket: WebSocket,
-    room_id: u64,
-    user: String,
-    chatroom_broadcaster: broadcast::Sender<ChatroomMessage>,
-    tweet_counter: Arc<AtomicU64>,
+    session: ChatSession,
+    state: AppState,
+    since: Option<Ulid>,
 ) {
-    let rx = chatroom_broadcaster.subscribe();
+    let room_id = session.room.id();
+    let user = session.user.clone();
+    // subscribe before replaying the history so no message falls in between
+    let rx = session.room.subscribe();
+    // sent to this member only
+    let (reply_tx, reply_rx) = mpsc::unbounded_channel();
 
     let (sender, receiver) = socket.split();
     let mut send_task = tokio::spawn(read_from_chatroom(
         receiver,
-        room_id,
-        user.clone(),
-        chatroom_broadcaster,
+        session.clone(),
+        state.clone(),
+        reply_tx,
     ));
     let mut recv_task = tokio::spawn(write_to_chatroom(
-        sender,
-        room_id,
-        user.clone(),
-        rx,
-        tweet_counter,
+        sender, session, rx, reply_rx, state, since,
     ));
 
     tokio::select! {
@@ -108,7 +198,8 @@ async fn handle_chatroom_socket(
                     tracing::error!("Room {}: User {}: Error sending messages", room_id, user.as_str());
                 },
             }
-            recv_task.abort();
+            // the write task stops once it has sent the remaining replies
+            let _ = recv_task.await;
         },
 
         rv_b = &mut recv_task => {
@@ -131,119 +222,358 @@ async fn handle_chatroom_socket(
     );
 }
 
-#[derive(Debug, Deserialize)]
-struct RecvMsg {
-    message: String,
-}
-
 async fn write_to_chatroom(
     mut sender: SplitSink<WebSocket, Message>,
-    room_id: u64,
-    user: String,
-    mut rx: broadcast::Receiver<ChatroomMessage>,
-    tweet_counter: Arc<AtomicU64>,
+    session: ChatSession,
+    mut rx: broadcast::Receiver<RoomEvent>,
+    mut replies: mpsc::UnboundedReceiver<Reply>,
+    state: AppState,
+    since: Option<Ulid>,
 ) {
-    while let Ok(send_msg) = rx.recv().await {
-        if send_msg.room != room_id {
-            continue;
+    let room_id = session.room.id();
+    let user = session.user.as_str();
+    let views = state.views;
+    let mut ping_timer = state.ws_config.ping_timer();
+    let mut replayed = HashSet::new();
+    if let Some(since) = since {
+        match replay_history(&mut sender, &session, &state.chat, since, &views).await {
+            Ok(ids) => replayed = ids,
+            Err(e) => {
+                tracing::error!(
+                    "Room {}: User {}: Failed to replay history: {:?}",
+                    room_id,
+                    user,
+                    e
+                );
+                return;
+            }
         }
+    }
 
-        let Ok(body) = serde_json::to_string(&send_msg.body) else {
-            tracing::error!(
-                "Room {}: User {}: Failed to serialize message",
-                room_id,
-                user
-            );
-            continue;
+    loop {
+        let event = tokio::select! {
+            event = rx.recv() => event,
+            reply = replies.recv() => {
+                match reply {
+                    Some(Reply::Frame(frame)) => {
+                        send_frame(&mut sender, &session, &frame).await;
+                    }
+                    Some(Reply::Close(close)) => {
+                        ws::send_close(&mut sender, close).await;
+                        break;
+                    }
+                    // the client is gone
+                    None => break,
+                }
+                continue;
+            }
+            _ = ping_timer.tick() => {
+                if let Err(e) = sender.send(Message::Ping(Vec::new())).await {
+                    tracing::debug!("Room {}: User {}: Failed to ping: {:?}", room_id, user, e);
+                    break;
+                }
+                continue;
+            }
+            _ = state.ws_shutdown.cancelled() => {
+                ws::send_close(&mut sender, WsShutdown::close_frame()).await;
+                break;
+            }
+            _ = session.kicked.cancelled() => {
+                let close = CloseFrame {
+                    code: close_code::POLICY,
+                    reason: f!("kicked from room {room_id} by a moderator").into(),
+                };
+                ws::send_close(&mut sender, close).await;
+                break;
+            }
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                tracing::warn!(
+                    "Room {}: User {}: Lagging behind, dropped {} messages",
+                    room_id,
+                    user,
+                    count
+                );
+                session.room.record_dropped(count);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        match event {
+            RoomEvent::Message(send_msg) => {
+                // already sent while replaying the history
+                if replayed.remove(&send_msg.id) {
+                    continue;
+                }
+                send_to_user(
+                    &mut sender,
+                    &session,
+                    RoomEvent::Message(send_msg).into(),
+                    &views,
+                )
+                .await;
+            }
+            // events about the user themselves are not echoed
+            RoomEvent::Join { user: ref other }
+            | RoomEvent::Leave { user: ref other }
+            | RoomEvent::Typing { user: ref other }
+                if session.presence && other != user =>
+            {
+                send_frame(&mut sender, &session, &event.into()).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Send the stored messages of the room sent after `since`, oldest first.
+///
+/// Returns the ids of the messages sent, which may also be received live.
+async fn replay_history(
+    sender: &mut SplitSink<WebSocket, Message>,
+    session: &ChatSession,
+    chat: &DynChatRepository,
+    since: Ulid,
+    views: &ViewCounts,
+) -> Result<HashSet<Ulid>> {
+    let mut last = None;
+    let mut replayed = HashSet::new();
+    loop {
+        let filter = MessageFilter {
+            after: Some(last.unwrap_or(since)),
+            limit: HISTORY_PAGE_SIZE,
         };
-        if let Err(e) = sender.send(Message::Text(body)).await {
+        let page = chat.messages(session.room.id(), filter).await?;
+        for message in page.iter().cloned() {
+            last = Some(message.id);
+            replayed.insert(message.id);
+            let frame = RoomEvent::Message(message.into()).into();
+            send_to_user(sender, session, frame, views).await;
+        }
+
+        if (page.len() as i64) < HISTORY_PAGE_SIZE {
+            return Ok(replayed);
+        }
+    }
+}
+
+/// Send a chat message, counting it as viewed.
+async fn send_to_user(
+    sender: &mut SplitSink<WebSocket, Message>,
+    session: &ChatSession,
+    frame: ServerFrame,
+    views: &ViewCounts,
+) {
+    if send_frame(sender, session, &frame).await {
+        if let ServerFrame::Message { user: author, .. } = &frame {
+            views.record(session.room.id(), author);
+        }
+    }
+}
+
+/// Returns whether the frame was sent; legacy clients are not sent acks and errors.
+async fn send_frame(
+    sender: &mut SplitSink<WebSocket, Message>,
+    session: &ChatSession,
+    frame: &ServerFrame,
+) -> bool {
+    let (room_id, user) = (session.room.id(), session.user.as_str());
+    let text = match session.protocol.encode(frame, session.presence) {
+        Ok(Some(text)) => text,
+        Ok(None) => return false,
+        Err(e) => {
             tracing::error!(
-                "Room {}: User {}: Failed to send message: {:?}",
+                "Room {}: User {}: Failed to serialize message: {:?}",
                 room_id,
                 user,
                 e
-            )
-        } else {
-            tweet_counter.fetch_add(1, atomic::Ordering::Relaxed);
-
-            tracing::trace!(
-                "Room {}: User {}: Received message: {:?}",
-                room_id,
-                user,
-                send_msg
             );
+            return false;
         }
+    };
+    tracing::trace!("Room {}: User {}: Sending {}", room_id, user, text);
+
+    if let Err(e) = sender.send(Message::Text(text)).await {
+        tracing::error!(
+            "Room {}: User {}: Failed to send message: {:?}",
+            room_id,
+            user,
+            e
+        );
+        return false;
     }
+
+    true
 }
 
 async fn read_from_chatroom(
     mut receiver: SplitStream<WebSocket>,
-    room_id: u64,
-    user: String,
-    tx: broadcast::Sender<ChatroomMessage>,
+    session: ChatSession,
+    state: AppState,
+    replies: mpsc::UnboundedSender<Reply>,
 ) {
-    while let Some(msg) = receiver.next().await {
-        if let Err(e) = msg {
-            tracing::error!(
-                "Room {}: User {}: Error receiving message: {:?}",
-                room_id,
-                user.clone(),
-                e
-            );
-            continue;
-        }
+    let ChatSession { room, user, .. } = &session;
+    let room_id = room.id();
+    let (chat, chatrooms, config, ws_config) = (
+        state.chat,
+        state.chatrooms,
+        state.chat_config,
+        state.ws_config,
+    );
+    let mut limiter = config.user_rate_limit.map(TokenBucket::new);
+    let mut strikes = Strikes::new(config.strike_window);
+    // failing only means the write task has already stopped
+    let reply = |frame: ServerFrame| {
+        let _ = replies.send(Reply::Frame(frame));
+    };
+    loop {
+        let Ok(msg) = tokio::time::timeout(ws_config.idle_timeout, receiver.next()).await else {
+            tracing::debug!("Room {}: User {}: Idle, disconnecting", room_id, user);
+            let _ = replies.send(Reply::Close(ws_config.idle_close()));
+            break;
+        };
+        let Some(msg) = msg else {
+            break;
+        };
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::error!(
+                    "Room {}: User {}: Error receiving message: {:?}",
+                    room_id,
+                    user,
+                    e
+                );
+                continue;
+            }
+        };
 
         tracing::trace!(
             "Room {}: Received message from User {}: {:?}",
             room_id,
-            user.clone(),
+            user,
             msg
         );
 
-        match msg.unwrap() {
+        match msg {
             Message::Text(t) => {
-                let Ok(recv_msg) = serde_json::from_str::<RecvMsg>(t.as_str()) else {
-                    tracing::error!(
-                        "Room {}: User {}: Failed to parse message: {:?}",
-                        room_id,
-                        user.clone(),
-                        t
-                    );
-                    continue;
+                let frame = match session.protocol.parse(&t) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        tracing::debug!(
+                            "Room {}: User {}: Rejected frame {:?}: {}",
+                            room_id,
+                            user,
+                            t,
+                            e.message
+                        );
+                        reply(e.into());
+                        continue;
+                    }
                 };
 
-                tracing::debug!(
-                    "Room {}: User {}: Received message: {:?}",
-                    room_id,
-                    user.clone(),
-                    recv_msg
-                );
-
-                if recv_msg.message.len() > 128 {
-                    tracing::error!(
-                        "Room {}: User {}: Message too long: {:?}",
-                        room_id,
-                        user.clone(),
-                        recv_msg
-                    );
-                    continue;
+                if let ClientFrame::Message { .. } | ClientFrame::Typing = frame {
+                    if !limiter.as_mut().is_none_or(TokenBucket::try_take) {
+                        let strikes = strikes.add();
+                        tracing::debug!(
+                            "Room {}: User {}: Rate limited ({} strikes)",
+                            room_id,
+                            user,
+                            strikes
+                        );
+                        if strikes > config.max_strikes {
+                            let close = CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "rate limit exceeded too many times".into(),
+                            };
+                            let _ = replies.send(Reply::Close(close));
+                            break;
+                        }
+                        let e = FrameError::new(
+                            ErrorCode::RateLimited,
+                            "sending too fast, wait before sending again",
+                        );
+                        reply(e.with_id(frame.id()).into());
+                        continue;
+                    }
+                    if !chatrooms.try_acquire(room_id) {
+                        let e = FrameError::new(
+                            ErrorCode::RateLimited,
+                            f!("room {room_id} is too busy, try again later"),
+                        );
+                        reply(e.with_id(frame.id()).into());
+                        continue;
+                    }
                 }
 
-                let body = ChatroomMessageBody {
-                    user: user.clone(),
-                    message: recv_msg.message,
-                };
-                let send_msg = ChatroomMessage {
-                    room: room_id,
-                    body,
-                };
-                if let Err(e) = tx.send(send_msg) {
-                    tracing::error!(
-                        "Room {}: User {}: Failed to send message: {:?}",
-                        room_id,
-                        user.clone(),
-                        e
-                    )
+                match frame {
+                    ClientFrame::Typing => room.send(RoomEvent::Typing { user: user.clone() }),
+                    ClientFrame::Ping { id } => reply(ServerFrame::Ack {
+                        id,
+                        message_id: None,
+                    }),
+                    ClientFrame::Message { id, message } => {
+                        tracing::debug!(
+                            "Room {}: User {}: Received message: {:?}",
+                            room_id,
+                            user,
+                            message
+                        );
+                        if let Err(e) = config.check_message(&message) {
+                            tracing::debug!(
+                                "Room {}: User {}: Rejected message: {}",
+                                room_id,
+                                user,
+                                e.message
+                            );
+                            reply(e.with_id(id.as_deref()).into());
+                            continue;
+                        }
+                        if chatrooms.is_muted(room_id, user) {
+                            let e = FrameError::new(
+                                ErrorCode::Muted,
+                                f!("you are muted in room {room_id}"),
+                            );
+                            reply(e.with_id(id.as_deref()).into());
+                            continue;
+                        }
+                        // escaping can make the message longer than was allowed
+                        let message =
+                            match state.moderation.apply(message).and_then(|message| {
+                                config.check_message(&message).map(|()| message)
+                            }) {
+                                Ok(message) => message,
+                                Err(e) => {
+                                    tracing::debug!(
+                                        "Room {}: User {}: Message rejected by moderation: {}",
+                                        room_id,
+                                        user,
+                                        e.message
+                                    );
+                                    reply(e.with_id(id.as_deref()).into());
+                                    continue;
+                                }
+                            };
+
+                        let message = ChatMessage::new(room_id, user.clone(), message);
+                        if let Err(e) = chat.insert_message(&message).await {
+                            tracing::error!(
+                                "Room {}: User {}: Failed to store message: {:?}",
+                                room_id,
+                                user,
+                                e
+                            );
+                        }
+
+                        let message_id = message.id;
+                        room.send(RoomEvent::Message(message.into()));
+                        reply(ServerFrame::Ack {
+                            id,
+                            message_id: Some(message_id),
+                        });
+                    }
                 }
             }
 
@@ -269,12 +599,96 @@ async fn read_from_chatroom(
     }
 }
 
-pub async fn get_tweet_view_count(State(state): State<AppState>) -> Result<String> {
-    let count = state.chatroom_counter.load(atomic::Ordering::Relaxed);
-    tracing::trace!("Tweet view count: {}", count);
+/// Views of the messages sent in a room and/or by a user, all of them by default.
+pub async fn get_tweet_view_count(
+    State(views): State<Arc<ViewCounts>>,
+    Query(filter): Query<ViewFilter>,
+) -> Result<String> {
+    let count = views.total(&filter);
+    tracing::trace!("Tweet view count for {:?}: {}", filter, count);
     Ok(count.to_string())
 }
 
-pub async fn reset_tweet_view_count(State(state): State<AppState>) {
-    state.chatroom_counter.store(0, atomic::Ordering::Relaxed);
+#[derive(Debug, Deserialize)]
+pub struct ResetViewsQuery {
+    /// Only reset the views of this room.
+    room: Option<u64>,
+}
+
+pub async fn reset_tweet_view_count(
+    _: Authorized<AdminReset>,
+    State(views): State<Arc<ViewCounts>>,
+    Query(query): Query<ResetViewsQuery>,
+) {
+    views.reset(query.room);
+}
+
+/// Members, messages and dropped messages of the rooms with members.
+pub async fn get_rooms_metrics(State(chatrooms): State<Arc<ChatRooms>>) -> Json<Vec<RoomMetrics>> {
+    Json(chatrooms.metrics())
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomMembers {
+    room: u64,
+    members: Vec<String>,
+}
+
+/// Names of the users currently in a room.
+pub async fn get_room_members(
+    State(chatrooms): State<Arc<ChatRooms>>,
+    Path(room_id): Path<u64>,
+) -> Json<RoomMembers> {
+    let members = chatrooms
+        .get(room_id)
+        .map(|room| room.members())
+        .unwrap_or_default();
+
+    Json(RoomMembers {
+        room: room_id,
+        members,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MuteQuery {
+    /// Mute for this long, until unmuted without it.
+    secs: Option<u64>,
+}
+
+/// Refuse the messages of a user in a room, whether or not they are in it.
+pub async fn mute_member(
+    _: Authorized<ChatModerate>,
+    State(chatrooms): State<Arc<ChatRooms>>,
+    Path((room_id, user)): Path<(u64, String)>,
+    Query(query): Query<MuteQuery>,
+) -> Result<StatusCode> {
+    chatrooms.mute(room_id, &user, query.secs.map(Duration::from_secs))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unmute_member(
+    _: Authorized<ChatModerate>,
+    State(chatrooms): State<Arc<ChatRooms>>,
+    Path((room_id, user)): Path<(u64, String)>,
+) -> Result<StatusCode> {
+    if !chatrooms.unmute(room_id, &user) {
+        return Err(AppError::NotFound(f!(
+            "{user} is not muted in room {room_id}"
+        )));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Disconnect a member from a room; it may join again unless muted.
+pub async fn kick_member(
+    _: Authorized<ChatModerate>,
+    State(chatrooms): State<Arc<ChatRooms>>,
+    Path((room_id, user)): Path<(u64, String)>,
+) -> Result<StatusCode> {
+    let kicked = chatrooms.get(room_id).is_some_and(|room| room.kick(&user));
+    if !kicked {
+        return Err(AppError::NotFound(f!("{user} is not in room {room_id}")));
+    }
+    Ok(StatusCode::NO_CONTENT)
 }
@@ -1,131 +1,227 @@
-use std::{fs, io::Cursor, path::PathBuf};
+use std::{io::Read, sync::Arc};
 
 use anyhow::Context;
-use axum::body;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    Json,
+};
 use git2::Repository;
+use serde::Deserialize;
 use tar::Archive;
-use tempfile::TempDir;
 
-use crate::prelude::*;
+use crate::{
+    archive::{self, ArchiveLimits, Manifest},
+    git::{
+        self,
+        analytics::{self, AnalyticsOptions, RepoAnalytics},
+        search::{Search, SearchResult},
+        UploadedRepo,
+    },
+    prelude::*,
+};
+
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
 
-pub async fn count_archive_files(bytes: body::Bytes) -> Result<String> {
-    let mut a = Archive::new(Cursor::new(bytes));
-    let counter = a
-        .entries()
-        .map_err(|e| anyhow::anyhow!("failed to count files: {e}"))?
-        .count();
-    Ok(counter.to_string())
+/// Number of entries and sum of their sizes, reading the body as it arrives.
+async fn scan_archive(
+    headers: &HeaderMap,
+    body: Body,
+    limits: Arc<ArchiveLimits>,
+) -> Result<(usize, u64)> {
+    let content_type = content_type(headers);
+    let body = archive::blocking_reader(body);
+    tokio::task::spawn_blocking(move || {
+        let mut a = Archive::new(archive::open(body, content_type.as_deref(), &limits)?);
+        let (mut count, mut size) = (0, 0);
+        for entry in a.entries().map_err(archive::invalid)? {
+            count += 1;
+            size += entry.map_err(archive::invalid)?.size();
+        }
+        Ok((count, size))
+    })
+    .await
+    .context("scan archive")?
 }
 
-pub async fn count_archive_files_size(bytes: body::Bytes) -> Result<String> {
-    let mut a = Archive::new(Cursor::new(bytes));
-    let entries = a
-        .entries()
-        .map_err(|e| anyhow::anyhow!("failed to count files: {e}"))?;
-    let mut size = 0;
-    for file in entries {
-        size += file?.size();
-    }
+pub async fn count_archive_files(
+    State(limits): State<Arc<ArchiveLimits>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<String> {
+    let (count, _) = scan_archive(&headers, body, limits).await?;
+    Ok(count.to_string())
+}
 
+pub async fn count_archive_files_size(
+    State(limits): State<Arc<ArchiveLimits>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<String> {
+    let (_, size) = scan_archive(&headers, body, limits).await?;
     Ok(size.to_string())
 }
 
-pub async fn get_cookie_from_archive_file(bytes: body::Bytes) -> Result<String> {
-    // save the archive to a temporary directory
-    let tmp = TempDir::new()?;
-    let path = tmp.path().join(f!("repo/{}", uuid::Uuid::new_v4()));
-    let mut a = Archive::new(Cursor::new(bytes));
-    a.unpack(&path).context("failed to unpack archive")?;
-
-    // open the repository and switch to the christmas branch
-    let repo = Repository::open(&path).context("open repository")?;
-    checkout_to_branch_christmas(&repo).context("checkout to christmas")?;
-
-    // traverse the commit history
-    let revwalk = prepare_revwalk_for_traversal(&repo).context("prepare revwalk")?;
-    for oid in revwalk {
-        let oid = oid.context("get commit")?;
-        let commit = repo.find_commit(oid).context("find git commmit")?;
-        tracing::trace!("commit: {} {}", commit.author(), commit.id());
-
-        // checkout the commit
-        checkout_to_commit(&repo, &commit)?;
-
-        // check if the cookie exists
-        let has_found_cookie = has_found_cookie(&path).context("check for cookie")?;
-        if has_found_cookie {
-            let author = commit.author();
-            let name = author.name().context("get author name")?;
-            return Ok(f!("{} {}", name, commit.id()));
-        }
-    }
-
-    Ok(String::from("No cookie found"))
+/// Entries of an archive, with the reason unpacking any of them would be unsafe.
+pub async fn inspect_archive(
+    State(limits): State<Arc<ArchiveLimits>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<Manifest>> {
+    let content_type = content_type(&headers);
+    let body = archive::blocking_reader(body);
+    let manifest = tokio::task::spawn_blocking(move || {
+        archive::inspect(
+            archive::open(body, content_type.as_deref(), &limits)?,
+            &limits,
+        )
+    })
+    .await
+    .context("inspect archive")??;
+    Ok(Json(manifest))
 }
 
-fn checkout_to_branch_christmas(repo: &git2::Repository) -> Result<()> {
-    let branch = repo
-        .find_branch("christmas", git2::BranchType::Local)
-        .context("find christmas branch")?;
-    let Some(branch_name) = branch.name().context("get branch name")? else {
-        return Err(AppError::Internal(anyhow::anyhow!(
-            "get branch name failed"
-        )));
-    };
-    repo.set_head(f!("refs/heads/{branch_name}").as_str())
-        .context("set head to christmas")?;
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    /// Branch, tag or commit to search the history of.
+    #[serde(rename = "ref", default = "default_ref")]
+    reference: String,
+    /// Glob of the paths to search, e.g. `**/santa.txt`; every file without it.
+    path: Option<String>,
+    /// Regex the lines must match.
+    pattern: String,
+    limit: Option<usize>,
+}
 
-    Ok(())
+fn default_ref() -> String {
+    "HEAD".to_string()
 }
 
-fn prepare_revwalk_for_traversal(repo: &git2::Repository) -> Result<git2::Revwalk> {
-    let mut revwalk = repo.revwalk().context("get commit history")?;
-    revwalk
-        .set_sorting(git2::Sort::TIME)
-        .context("set reop revwalk sorting")?;
-    revwalk.push_head().context("push head to revwalk")?;
+const DEFAULT_SEARCH_LIMIT: usize = 100;
+const MAX_SEARCH_LIMIT: usize = 10_000;
 
-    Ok(revwalk)
+/// Refuse a `name` parameter outside of `1..=max`.
+fn check_limit(name: &str, value: usize, max: usize) -> Result<usize> {
+    if !(1..=max).contains(&value) {
+        return Err(AppError::BadRequest(f!(
+            "{name} must be between 1 and {max}, got {value}"
+        )));
+    }
+    Ok(value)
 }
 
-fn checkout_to_commit(repo: &git2::Repository, commit: &git2::Commit) -> Result<()> {
-    let tree = commit.tree().context("get commit tree")?;
-    repo.checkout_tree(
-        tree.as_object(),
-        Some(&mut git2::build::CheckoutBuilder::new().force()),
-    )
-    .context("checkout tree")?;
-    repo.set_head_detached(commit.id())
-        .context("set head detached")?;
-
-    Ok(())
+/// Unpack the repository uploaded as an archive and run `f` on it, on a blocking thread.
+async fn with_uploaded_repo<R, T, F>(
+    headers: &HeaderMap,
+    body: R,
+    limits: Arc<ArchiveLimits>,
+    f: F,
+) -> Result<T>
+where
+    R: Read + Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&Repository) -> Result<T> + Send + 'static,
+{
+    let content_type = content_type(headers);
+    tokio::task::spawn_blocking(move || {
+        let tar = archive::open(body, content_type.as_deref(), &limits)?;
+        let uploaded = UploadedRepo::unpack(tar, &limits)?;
+        f(uploaded.repo())
+    })
+    .await
+    .context("read uploaded repository")?
 }
 
-fn has_found_cookie(path: &PathBuf) -> Result<bool> {
-    if !path.is_dir() {
-        return Ok(false);
-    }
+/// Lines matching `pattern` in the files matching `path`, in every commit of `ref`.
+pub async fn search_repository(
+    State(limits): State<Arc<ArchiveLimits>>,
+    Query(query): Query<SearchQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<SearchResult>> {
+    let limit = check_limit(
+        "limit",
+        query.limit.unwrap_or(DEFAULT_SEARCH_LIMIT),
+        MAX_SEARCH_LIMIT,
+    )?;
+    let search = Search::new(query.path.as_deref(), &query.pattern, limit)?;
+
+    let body = archive::blocking_reader(body);
+    let result = with_uploaded_repo(&headers, body, limits, move |repo| {
+        search.run(repo, &git::resolve(repo, &query.reference)?)
+    })
+    .await?;
+    Ok(Json(result))
+}
 
-    for entry in fs::read_dir(path).context("read dir")? {
-        let path = entry?.path();
-        if path.file_name() == Some(".git".as_ref()) {
-            continue;
-        }
+#[derive(Debug, Deserialize)]
+pub struct AnalyticsQuery {
+    #[serde(rename = "ref", default = "default_ref")]
+    reference: String,
+    /// Also report the commits changing this path.
+    path: Option<String>,
+    /// Most commits listed.
+    limit: Option<usize>,
+    /// Most files listed.
+    top: Option<usize>,
+}
 
-        if path.is_dir() {
-            let result = has_found_cookie(&path)?;
-            if result {
-                return Ok(true);
-            } else {
-                continue;
-            }
-        }
+const DEFAULT_ANALYTICS_COMMITS: usize = 100;
+const MAX_ANALYTICS_COMMITS: usize = 10_000;
+const DEFAULT_ANALYTICS_FILES: usize = 10;
+const MAX_ANALYTICS_FILES: usize = 1_000;
+
+/// Authors, churn, most changed files, branches, tags and optionally the
+/// history of a path, of a repository uploaded as an archive.
+pub async fn analyze_repository(
+    State(limits): State<Arc<ArchiveLimits>>,
+    Query(query): Query<AnalyticsQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<RepoAnalytics>> {
+    let options = AnalyticsOptions {
+        path: query.path,
+        commits: check_limit(
+            "limit",
+            query.limit.unwrap_or(DEFAULT_ANALYTICS_COMMITS),
+            MAX_ANALYTICS_COMMITS,
+        )?,
+        files: check_limit(
+            "top",
+            query.top.unwrap_or(DEFAULT_ANALYTICS_FILES),
+            MAX_ANALYTICS_FILES,
+        )?,
+    };
 
-        if path.file_name() == Some("santa.txt".as_ref()) {
-            let content = fs::read_to_string(path).context("read santa.txt")?;
-            return Ok(content.contains("COOKIE"));
-        }
-    }
+    let body = archive::blocking_reader(body);
+    let analytics = with_uploaded_repo(&headers, body, limits, move |repo| {
+        analytics::analyze(repo, &git::resolve(repo, &query.reference)?, &options)
+    })
+    .await?;
+    Ok(Json(analytics))
+}
 
-    Ok(false)
+/// Author and id of the latest commit of `christmas` with `COOKIE` in a `santa.txt`.
+pub async fn get_cookie_from_archive_file(
+    State(limits): State<Arc<ArchiveLimits>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<String> {
+    let search = Search::new(Some("**/santa.txt"), "COOKIE", 1)?;
+    let body = archive::blocking_reader(body);
+    let result = with_uploaded_repo(&headers, body, limits, move |repo| {
+        search.run(repo, &git::resolve(repo, "christmas")?)
+    })
+    .await?;
+
+    Ok(match result.matches.first() {
+        Some(found) => f!("{} {}", found.author, found.commit),
+        None => String::from("No cookie found"),
+    })
 }
@@ -15,7 +15,7 @@ pub async fn get_country_from_coords(
 ) -> Result<String> {
     let lat_lng = parse_coordinates_from_s2_cell_id(&cell_id)?;
 
-    let Some(api_key) = state.secrect_store.get("MAPSCO_API_KEY") else {
+    let Some(api_key) = state.secrets.get("MAPSCO_API_KEY") else {
         return Err(AppError::Internal(anyhow::anyhow!(
             "Failed to get MAPSCO_API_KEY from secret store"
         )));
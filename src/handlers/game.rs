@@ -0,0 +1,154 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Query, State, WebSocketUpgrade,
+    },
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use ulid::Ulid;
+
+use crate::{
+    game::{ClientFrame, ErrorCode, Games, PlayerStats, ServerFrame},
+    prelude::*,
+    ws::{self, WsConfig, WsShutdown},
+};
+
+const DEFAULT_LEADERBOARD_SIZE: usize = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct GameQuery {
+    player: String,
+    /// Session to join, a new one is opened without it.
+    session: Option<Ulid>,
+}
+
+/// Play against another client, with [`ClientFrame`]s in and [`ServerFrame`]s out.
+pub async fn game_socket(
+    ws: WebSocketUpgrade,
+    Query(query): Query<GameQuery>,
+    State(games): State<Arc<Games>>,
+    State(config): State<Arc<WsConfig>>,
+    State(shutdown): State<WsShutdown>,
+) -> Result<impl IntoResponse> {
+    if query.player.trim().is_empty() {
+        return Err(AppError::BadRequest("player must not be empty".to_string()));
+    }
+
+    Ok(ws.on_upgrade(move |socket| {
+        shutdown.track(play(socket, games, query, config, shutdown.clone()))
+    }))
+}
+
+async fn play(
+    mut socket: WebSocket,
+    games: Arc<Games>,
+    query: GameQuery,
+    config: Arc<WsConfig>,
+    shutdown: WsShutdown,
+) {
+    let session = query.session.unwrap_or_else(|| games.create());
+    let mut player = match games.join(session, &query.player).await {
+        Ok(player) => player,
+        Err(e) => {
+            tracing::debug!("Game {}: {} rejected: {}", session, query.player, e);
+            let close = CloseFrame {
+                code: close_code::POLICY,
+                reason: e.to_string().into(),
+            };
+            ws::send_close(&mut socket, close).await;
+            return;
+        }
+    };
+
+    let mut ping_timer = config.ping_timer();
+    let idle = tokio::time::sleep(config.idle_timeout);
+    tokio::pin!(idle);
+    loop {
+        let msg = tokio::select! {
+            msg = socket.recv() => msg,
+            frame = player.next_frame() => {
+                let Some(frame) = frame else {
+                    let close = CloseFrame {
+                        code: close_code::NORMAL,
+                        reason: "game over".into(),
+                    };
+                    ws::send_close(&mut socket, close).await;
+                    return;
+                };
+                if !send_frame(&mut socket, &frame).await {
+                    return;
+                }
+                continue;
+            }
+            _ = ping_timer.tick() => {
+                if let Err(e) = socket.send(Message::Ping(Vec::new())).await {
+                    tracing::debug!("Game {}: Failed to ping {}: {:?}", session, query.player, e);
+                    return;
+                }
+                continue;
+            }
+            _ = &mut idle => {
+                ws::send_close(&mut socket, config.idle_close()).await;
+                return;
+            }
+            _ = shutdown.cancelled() => {
+                ws::send_close(&mut socket, WsShutdown::close_frame()).await;
+                return;
+            }
+        };
+        let Some(Ok(msg)) = msg else {
+            return;
+        };
+        idle.as_mut()
+            .reset(tokio::time::Instant::now() + config.idle_timeout);
+
+        match msg {
+            Message::Text(t) => match serde_json::from_str::<ClientFrame>(&t) {
+                Ok(frame) => player.play(frame),
+                Err(e) => {
+                    let frame = ServerFrame::error(ErrorCode::InvalidFrame, e.to_string());
+                    if !send_frame(&mut socket, &frame).await {
+                        return;
+                    }
+                }
+            },
+            Message::Close(_) => return,
+            _ => {}
+        }
+    }
+}
+
+/// Returns whether the frame was sent.
+async fn send_frame(socket: &mut WebSocket, frame: &ServerFrame) -> bool {
+    let text = match serde_json::to_string(frame) {
+        Ok(text) => text,
+        Err(e) => {
+            tracing::error!("Failed to serialize game frame: {:?}", e);
+            return false;
+        }
+    };
+    if let Err(e) = socket.send(Message::Text(text)).await {
+        tracing::debug!("Failed to send game frame: {:?}", e);
+        return false;
+    }
+
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeaderboardQuery {
+    limit: Option<usize>,
+}
+
+/// Players with the most wins, then the most points.
+pub async fn get_leaderboard(
+    State(games): State<Arc<Games>>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Json<Vec<PlayerStats>> {
+    let limit = query.limit.unwrap_or(DEFAULT_LEADERBOARD_SIZE);
+    Json(games.leaderboard().top(limit))
+}
@@ -0,0 +1,367 @@
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{header, HeaderMap},
+    Json,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{Authorized, OrdersWrite},
+    prelude::*,
+    repo::{ConflictPolicy, DynOrderRepository, InsertReport, Order},
+};
+
+/// Number of orders sent to the database in one `INSERT`.
+const IMPORT_BATCH_SIZE: usize = 500;
+/// Longest line accepted, without its line break.
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ImportOrdersQuery {
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct LineError {
+    line: usize,
+    message: String,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ImportReport {
+    lines: usize,
+    #[serde(flatten)]
+    orders: InsertReport,
+    errors: Vec<LineError>,
+}
+
+/// Parses one order per line, either CSV (with a header line) or NDJSON.
+enum LineParser {
+    Csv {
+        reader: Box<csv_core::Reader>,
+        headers: Option<csv::StringRecord>,
+    },
+    Ndjson,
+}
+
+impl LineParser {
+    fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+        match mime {
+            "text/csv" => Ok(LineParser::Csv {
+                reader: Box::new(csv_core::Reader::new()),
+                headers: None,
+            }),
+            "application/x-ndjson" | "application/jsonl" => Ok(LineParser::Ndjson),
+            _ => Err(AppError::UnsupportedMediaType(f!(
+                "expected text/csv or application/x-ndjson, got {content_type:?}"
+            ))),
+        }
+    }
+
+    /// Returns `Ok(None)` for lines that do not hold an order, like the CSV header.
+    fn parse(&mut self, line: &str) -> std::result::Result<Option<Order>, String> {
+        match self {
+            LineParser::Ndjson => serde_json::from_str(line)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            LineParser::Csv { reader, headers } => {
+                let record = read_csv_record(reader, line)?;
+
+                match headers {
+                    None => {
+                        *headers = Some(record.iter().map(str::trim).collect());
+                        Ok(None)
+                    }
+                    Some(headers) => record
+                        .deserialize(Some(headers))
+                        .map(Some)
+                        .map_err(|e| e.to_string()),
+                }
+            }
+        }
+    }
+}
+
+/// Split one line into CSV fields, reusing the same reader for every line.
+fn read_csv_record(
+    reader: &mut csv_core::Reader,
+    line: &str,
+) -> std::result::Result<csv::StringRecord, String> {
+    use csv_core::ReadRecordResult;
+
+    reader.reset();
+    // unescaped fields are never longer than the line
+    let mut output = vec![0; line.len()];
+    let mut ends = vec![0; line.len() + 1];
+    let (mut input, mut written, mut ended) = (line.as_bytes(), 0, 0);
+    loop {
+        let (result, read, out, end) =
+            reader.read_record(input, &mut output[written..], &mut ends[ended..]);
+        input = &input[read..];
+        written += out;
+        ended += end;
+        match result {
+            // an empty input ends the record on the next call
+            ReadRecordResult::InputEmpty => {}
+            ReadRecordResult::Record | ReadRecordResult::End => break,
+            ReadRecordResult::OutputFull | ReadRecordResult::OutputEndsFull => {
+                unreachable!("output buffers fit the whole line")
+            }
+        }
+    }
+
+    let mut start = 0;
+    let record = ends[..ended]
+        .iter()
+        .map(|&end| {
+            let field = &output[start..end];
+            start = end;
+            field
+        })
+        .collect::<csv::ByteRecord>();
+    csv::StringRecord::from_byte_record(record).map_err(|e| e.to_string())
+}
+
+/// Import orders from a `text/csv` or `application/x-ndjson` body.
+///
+/// The body is read as a stream and orders are inserted in batches, so a
+/// malformed or conflicting line is reported without aborting the import.
+/// CSV fields must not contain line breaks, and lines longer than
+/// [`MAX_LINE_BYTES`] abort the import.
+pub async fn import_orders(
+    _: Authorized<OrdersWrite>,
+    State(repo): State<DynOrderRepository>,
+    Query(query): Query<ImportOrdersQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<ImportReport>> {
+    let mut parser = LineParser::from_headers(&headers)?;
+    let mut importer = Importer::new(repo, query.on_conflict);
+
+    let mut stream = body.into_data_stream();
+    let mut buffer = Vec::<u8>::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| AppError::BadRequest(f!("failed to read body: {e}")))?;
+        // only the new bytes can hold a line break
+        let mut scanned = buffer.len();
+        buffer.extend_from_slice(&chunk);
+
+        let mut start = 0;
+        while let Some(pos) = buffer[scanned..].iter().position(|b| *b == b'\n') {
+            let end = scanned + pos + 1;
+            importer.push_line(&mut parser, &buffer[start..end]).await?;
+            start = end;
+            scanned = end;
+        }
+        buffer.drain(..start);
+        if buffer.len() > MAX_LINE_BYTES {
+            return Err(importer.line_too_long());
+        }
+    }
+    if !buffer.is_empty() {
+        importer.push_line(&mut parser, &buffer).await?;
+    }
+
+    Ok(Json(importer.finish().await?))
+}
+
+struct Importer {
+    repo: DynOrderRepository,
+    on_conflict: ConflictPolicy,
+    batch: Vec<(usize, Order)>,
+    report: ImportReport,
+}
+
+impl Importer {
+    fn new(repo: DynOrderRepository, on_conflict: ConflictPolicy) -> Self {
+        Self {
+            repo,
+            on_conflict,
+            batch: Vec::with_capacity(IMPORT_BATCH_SIZE),
+            report: ImportReport::default(),
+        }
+    }
+
+    async fn push_line(&mut self, parser: &mut LineParser, line: &[u8]) -> Result<()> {
+        if line.strip_suffix(b"\n").unwrap_or(line).len() > MAX_LINE_BYTES {
+            return Err(self.line_too_long());
+        }
+        self.report.lines += 1;
+        let line_number = self.report.lines;
+
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => line.trim_end_matches(['\n', '\r']),
+            Err(e) => {
+                self.line_error(line_number, e.to_string());
+                return Ok(());
+            }
+        };
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+
+        let order = match parser.parse(line) {
+            Ok(Some(order)) => order,
+            Ok(None) => return Ok(()),
+            Err(message) => {
+                self.line_error(line_number, message);
+                return Ok(());
+            }
+        };
+        if let Err(e) = order.validate() {
+            self.line_error(line_number, e.to_string());
+            return Ok(());
+        }
+
+        self.batch.push((line_number, order));
+        if self.batch.len() >= IMPORT_BATCH_SIZE {
+            self.flush().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let orders = batch.iter().map(|(_, o)| o.clone()).collect();
+
+        match self.repo.insert_many(orders, self.on_conflict).await {
+            Ok(report) => self.merge(report),
+            // find out which lines are to blame by inserting them one by one
+            Err(AppError::Conflict(_) | AppError::BadRequest(_)) => {
+                for (line_number, order) in batch {
+                    match self.repo.insert_many(vec![order], self.on_conflict).await {
+                        Ok(report) => self.merge(report),
+                        Err(e @ (AppError::Conflict(_) | AppError::BadRequest(_))) => {
+                            self.line_error(line_number, e.to_string())
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> Result<ImportReport> {
+        self.flush().await?;
+        self.report.errors.sort_by_key(|e| e.line);
+
+        Ok(self.report)
+    }
+
+    fn merge(&mut self, report: InsertReport) {
+        let orders = &mut self.report.orders;
+        orders.inserted.extend(report.inserted);
+        orders.updated.extend(report.updated);
+        orders.skipped.extend(report.skipped);
+    }
+
+    fn line_too_long(&self) -> AppError {
+        AppError::PayloadTooLarge(f!(
+            "line {} is longer than {MAX_LINE_BYTES} bytes",
+            self.report.lines + 1
+        ))
+    }
+
+    fn line_error(&mut self, line: usize, message: String) {
+        tracing::debug!("Import line {}: {}", line, message);
+        self.report.errors.push(LineError { line, message });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{auth::Principal, repo::InMemoryRepository};
+
+    async fn import(
+        repo: &DynOrderRepository,
+        content_type: &'static str,
+        body: impl Into<Body>,
+    ) -> Result<ImportReport> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+        let Json(report) = import_orders(
+            Authorized::new(Principal::unrestricted()),
+            State(repo.clone()),
+            Query(ImportOrdersQuery::default()),
+            headers,
+            body.into(),
+        )
+        .await?;
+        Ok(report)
+    }
+
+    #[tokio::test]
+    async fn test_import_csv() {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+        let body = "id,region_id,gift_name,quantity\r
+1,1,Toy Train,5\r
+2,1,\"Doll, large\",x\r
+\r
+3,2,Doll,-1\r
+1,2,Doll,3\r
+4,2,Doll,3";
+
+        let report = import(&repo, "text/csv; charset=utf-8", body)
+            .await
+            .unwrap();
+        assert_eq!(report.lines, 7);
+        assert_eq!(report.orders.inserted, vec![1, 4]);
+        let lines = report.errors.iter().map(|e| e.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![3, 5, 6]);
+        assert_eq!(repo.total_quantity().await.unwrap(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_import_ndjson() {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+        let body = r#"{"id":1,"region_id":1,"gift_name":"Toy Train","quantity":5}
+{"id":2,"region_id":1}
+{"id":3,"region_id":2,"gift_name":"Doll","quantity":3}
+"#;
+
+        let report = import(&repo, "application/x-ndjson", body).await.unwrap();
+        assert_eq!(report.orders.inserted, vec![1, 3]);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].line, 2);
+    }
+
+    #[tokio::test]
+    async fn test_import_rejects_long_lines() {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+        let body =
+            "id,region_id,gift_name,quantity\n".to_string() + &"x".repeat(MAX_LINE_BYTES + 1);
+
+        let result = import(&repo, "text/csv", body).await;
+        assert!(matches!(result, Err(AppError::PayloadTooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn test_import_rejects_unknown_content_type() {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+
+        let result = import(&repo, "application/json", "[]").await;
+        assert!(matches!(result, Err(AppError::UnsupportedMediaType(_))));
+    }
+}
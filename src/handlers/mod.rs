@@ -1,3 +1,5 @@
+mod analytics;
+mod chat;
 mod day01;
 mod day04;
 mod day05;
@@ -14,7 +16,13 @@ mod day19;
 mod day20;
 mod day21;
 mod day22;
+mod game;
+mod import;
+mod orders;
+mod regions;
 
+pub use analytics::*;
+pub use chat::*;
 pub use day01::*;
 pub use day04::*;
 pub use day05::*;
@@ -31,24 +39,17 @@ pub use day19::*;
 pub use day20::*;
 pub use day21::*;
 pub use day22::*;
+pub use game::*;
+pub use import::*;
+pub use orders::*;
+pub use regions::*;
 
-use axum::{
-    http::{StatusCode, Uri},
-    Json,
-};
+use axum::http::Uri;
 
-use serde_json::json;
+use crate::prelude::*;
 
-pub async fn not_found_handler(uri: Uri) -> (StatusCode, Json<serde_json::Value>) {
-    tracing::info!("path not found: {}", uri.path());
-
-    (
-        StatusCode::NOT_FOUND,
-        Json(json!( {
-            "error": String::from("not_found"),
-            "message": Some(format!("Requested path `{}` not found.", uri.path())),
-        })),
-    )
+pub async fn not_found_handler(uri: Uri) -> AppError {
+    AppError::NotFound(format!("Requested path `{}` not found.", uri.path()))
 }
 
 pub async fn hello_world() -> &'static str {
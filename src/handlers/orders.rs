@@ -0,0 +1,198 @@
+use axum::{
+    extract::{self, Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{Authorized, OrdersWrite},
+    prelude::*,
+    repo::{DynOrderRepository, Order, OrderChanges, OrderFilter},
+};
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct ListOrdersQuery {
+    region_id: Option<i32>,
+    gift_name: Option<String>,
+    limit: Option<i64>,
+    cursor: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrdersPage {
+    orders: Vec<Order>,
+    next_cursor: Option<i32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OrderPayload {
+    region_id: i32,
+    gift_name: String,
+    quantity: i32,
+}
+
+pub async fn list_orders(
+    State(repo): State<DynOrderRepository>,
+    Query(query): Query<ListOrdersQuery>,
+) -> Result<Json<OrdersPage>> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+        return Err(AppError::BadRequest(f!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}, got {limit}"
+        )));
+    }
+
+    // fetch one extra row to know whether there is a next page
+    let filter = OrderFilter {
+        region_id: query.region_id,
+        gift_name: query.gift_name,
+        after: query.cursor,
+        limit: limit + 1,
+    };
+    let mut orders = repo.list(filter).await?;
+
+    let next_cursor = if orders.len() as i64 > limit {
+        orders.truncate(limit as usize);
+        orders.last().map(|o| o.id)
+    } else {
+        None
+    };
+
+    Ok(Json(OrdersPage {
+        orders,
+        next_cursor,
+    }))
+}
+
+pub async fn get_order(
+    State(repo): State<DynOrderRepository>,
+    Path(id): Path<i32>,
+) -> Result<Json<Order>> {
+    repo.get(id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(f!("order {id} not found")))
+}
+
+pub async fn create_order(
+    _: Authorized<OrdersWrite>,
+    State(repo): State<DynOrderRepository>,
+    extract::Json(order): extract::Json<Order>,
+) -> Result<(StatusCode, Json<Order>)> {
+    order.validate()?;
+    let order = repo.insert(order).await?;
+
+    Ok((StatusCode::CREATED, Json(order)))
+}
+
+pub async fn update_order(
+    _: Authorized<OrdersWrite>,
+    State(repo): State<DynOrderRepository>,
+    Path(id): Path<i32>,
+    extract::Json(payload): extract::Json<OrderPayload>,
+) -> Result<Json<Order>> {
+    let changes = OrderChanges {
+        region_id: Some(payload.region_id),
+        gift_name: Some(payload.gift_name),
+        quantity: Some(payload.quantity),
+    };
+
+    apply_changes(&repo, id, changes).await.map(Json)
+}
+
+pub async fn patch_order(
+    _: Authorized<OrdersWrite>,
+    State(repo): State<DynOrderRepository>,
+    Path(id): Path<i32>,
+    extract::Json(changes): extract::Json<OrderChanges>,
+) -> Result<Json<Order>> {
+    apply_changes(&repo, id, changes).await.map(Json)
+}
+
+/// Validate the order as it would be after `changes` before writing them.
+async fn apply_changes(repo: &DynOrderRepository, id: i32, changes: OrderChanges) -> Result<Order> {
+    let not_found = || AppError::NotFound(f!("order {id} not found"));
+    let current = repo.get(id).await?.ok_or_else(not_found)?;
+    current.with_changes(&changes).validate()?;
+
+    repo.update(id, changes).await?.ok_or_else(not_found)
+}
+
+pub async fn delete_order(
+    _: Authorized<OrdersWrite>,
+    State(repo): State<DynOrderRepository>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode> {
+    if !repo.delete(id).await? {
+        return Err(AppError::NotFound(f!("order {id} not found")));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        auth::Principal,
+        repo::{ConflictPolicy, InMemoryRepository},
+    };
+
+    #[tokio::test]
+    async fn test_patch_order_validates_the_patched_order() {
+        let repo: DynOrderRepository = Arc::new(InMemoryRepository::new());
+        let order = Order {
+            id: 1,
+            region_id: 1,
+            gift_name: "Doll".to_string(),
+            quantity: 2,
+            created_at: None,
+        };
+        repo.insert_many(vec![order], ConflictPolicy::Error)
+            .await
+            .unwrap();
+        let patch = |changes| {
+            patch_order(
+                Authorized::new(Principal::unrestricted()),
+                State(repo.clone()),
+                Path(1),
+                extract::Json(changes),
+            )
+        };
+
+        let negative = OrderChanges {
+            quantity: Some(-1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            patch(negative).await,
+            Err(AppError::BadRequest(_))
+        ));
+        let too_long = OrderChanges {
+            gift_name: Some("🎁".repeat(51)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            patch(too_long).await,
+            Err(AppError::BadRequest(_))
+        ));
+        assert!(matches!(
+            repo.get(1).await.unwrap(),
+            Some(Order { quantity: 2, .. })
+        ));
+
+        let Json(order) = patch(OrderChanges {
+            quantity: Some(3),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!((order.gift_name.as_str(), order.quantity), ("Doll", 3));
+    }
+}
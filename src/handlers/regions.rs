@@ -0,0 +1,139 @@
+use axum::{
+    extract::{self, Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::Deserialize;
+
+use crate::{
+    auth::{Authorized, RegionsWrite},
+    prelude::*,
+    repo::{validate_region_name, DynRegionRepository, Region},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct RegionPayload {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegionPatch {
+    name: Option<String>,
+}
+
+pub async fn list_regions(State(repo): State<DynRegionRepository>) -> Result<Json<Vec<Region>>> {
+    Ok(Json(repo.list().await?))
+}
+
+pub async fn get_region(
+    State(repo): State<DynRegionRepository>,
+    Path(id): Path<i32>,
+) -> Result<Json<Region>> {
+    repo.get(id)
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(f!("region {id} not found")))
+}
+
+pub async fn create_region(
+    _: Authorized<RegionsWrite>,
+    State(repo): State<DynRegionRepository>,
+    extract::Json(region): extract::Json<Region>,
+) -> Result<(StatusCode, Json<Region>)> {
+    region.validate()?;
+    let region = repo.insert(region).await?;
+
+    Ok((StatusCode::CREATED, Json(region)))
+}
+
+pub async fn update_region(
+    _: Authorized<RegionsWrite>,
+    State(repo): State<DynRegionRepository>,
+    Path(id): Path<i32>,
+    extract::Json(payload): extract::Json<RegionPayload>,
+) -> Result<Json<Region>> {
+    validate_region_name(id, &payload.name)?;
+    repo.rename(id, payload.name)
+        .await?
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(f!("region {id} not found")))
+}
+
+pub async fn patch_region(
+    _: Authorized<RegionsWrite>,
+    State(repo): State<DynRegionRepository>,
+    Path(id): Path<i32>,
+    extract::Json(patch): extract::Json<RegionPatch>,
+) -> Result<Json<Region>> {
+    let region = match patch.name {
+        Some(name) => {
+            validate_region_name(id, &name)?;
+            repo.rename(id, name).await?
+        }
+        None => repo.get(id).await?,
+    };
+
+    region
+        .map(Json)
+        .ok_or_else(|| AppError::NotFound(f!("region {id} not found")))
+}
+
+pub async fn delete_region(
+    _: Authorized<RegionsWrite>,
+    State(repo): State<DynRegionRepository>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode> {
+    if !repo.delete(id).await? {
+        return Err(AppError::NotFound(f!("region {id} not found")));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{auth::Principal, repo::InMemoryRepository};
+
+    #[tokio::test]
+    async fn test_region_names_must_fit_the_column() {
+        let repo: DynRegionRepository = Arc::new(InMemoryRepository::new());
+        let create = |name: &str| {
+            create_region(
+                Authorized::new(Principal::unrestricted()),
+                State(repo.clone()),
+                extract::Json(Region {
+                    id: 1,
+                    name: name.to_string(),
+                }),
+            )
+        };
+
+        assert!(matches!(create("").await, Err(AppError::BadRequest(_))));
+        assert!(matches!(
+            create(&"🎄".repeat(51)).await,
+            Err(AppError::BadRequest(_))
+        ));
+        let (status, _) = create(&"🎄".repeat(50)).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+
+        let rename = |name: &str| {
+            patch_region(
+                Authorized::new(Principal::unrestricted()),
+                State(repo.clone()),
+                Path(1),
+                extract::Json(RegionPatch {
+                    name: Some(name.to_string()),
+                }),
+            )
+        };
+        assert!(matches!(
+            rename(&"🎄".repeat(51)).await,
+            Err(AppError::BadRequest(_))
+        ));
+        let Json(region) = rename("North Pole").await.unwrap();
+        assert_eq!(region.name, "North Pole");
+    }
+}
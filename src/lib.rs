@@ -1,9 +1,22 @@
 pub mod app_state;
+pub mod archive;
+pub mod auth;
+pub mod chatroom;
+pub mod config;
 pub mod errors;
+pub mod game;
+pub mod git;
 pub mod handlers;
+pub mod persist;
 pub mod prelude;
 pub mod repo;
+mod router;
+mod server;
 pub mod utils;
+pub mod ws;
+
+pub use router::build_router;
+pub use server::serve;
 
 use std::{collections::HashMap, fmt::Display};
 
@@ -169,6 +182,7 @@ impl Serialize for ReindeerContestStats {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Default)]
 pub struct CookieIngredient {
     flour: u64,
@@ -194,6 +208,7 @@ impl CookieIngredient {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct Pokemon {
     id: u64,
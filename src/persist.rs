@@ -0,0 +1,100 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// A key/value store for small pieces of state that must survive restarts.
+///
+/// Implementations only deal with raw bytes; use the typed `save`/`load`
+/// helpers on `dyn PersistStore`, which encode values as JSON.
+pub trait PersistStore: Send + Sync {
+    fn save_bytes(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()>;
+    fn load_bytes(&self, key: &str) -> anyhow::Result<Vec<u8>>;
+}
+
+impl dyn PersistStore {
+    pub fn save<T: Serialize>(&self, key: &str, value: T) -> anyhow::Result<()> {
+        let bytes = serde_json::to_vec(&value).context("serialize persisted value")?;
+        self.save_bytes(key, bytes)
+    }
+
+    pub fn load<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<T> {
+        let bytes = self.load_bytes(key)?;
+        serde_json::from_slice(&bytes).context("deserialize persisted value")
+    }
+}
+
+/// Stores every key as a JSON file inside a local directory.
+#[derive(Debug, Clone)]
+pub struct FilePersist {
+    dir: PathBuf,
+}
+
+impl FilePersist {
+    pub fn new(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("create {}", dir.display()))?;
+
+        Ok(Self { dir })
+    }
+
+    fn storage_file(&self, key: &str) -> anyhow::Result<PathBuf> {
+        let path = self.dir.join(format!("{key}.json"));
+        if key.is_empty() || path.parent() != Some(self.dir.as_path()) {
+            anyhow::bail!("invalid key name: {key:?}");
+        }
+
+        Ok(path)
+    }
+}
+
+impl PersistStore for FilePersist {
+    fn save_bytes(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()> {
+        let path = self.storage_file(key)?;
+        fs::write(&path, value).with_context(|| format!("write {}", path.display()))
+    }
+
+    fn load_bytes(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        let path = self.storage_file(key)?;
+        fs::read(&path).with_context(|| format!("read {}", path.display()))
+    }
+}
+
+#[cfg(feature = "shuttle")]
+impl PersistStore for shuttle_persist::PersistInstance {
+    fn save_bytes(&self, key: &str, value: Vec<u8>) -> anyhow::Result<()> {
+        self.save(key, value).context("save to shuttle persist")
+    }
+
+    fn load_bytes(&self, key: &str) -> anyhow::Result<Vec<u8>> {
+        self.load(key).context("load from shuttle persist")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+
+    use super::*;
+
+    #[test]
+    fn test_file_persist_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist: Arc<dyn PersistStore> = Arc::new(FilePersist::new(dir.path()).unwrap());
+
+        let now = SystemTime::now();
+        persist.save("time", now).unwrap();
+        assert_eq!(persist.load::<SystemTime>("time").unwrap(), now);
+        assert!(persist.load::<SystemTime>("missing").is_err());
+    }
+
+    #[test]
+    fn test_file_persist_rejects_paths_as_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let persist = FilePersist::new(dir.path()).unwrap();
+
+        assert!(persist.save_bytes("../escape", vec![]).is_err());
+        assert!(persist.save_bytes("nested/key", vec![]).is_err());
+        assert!(persist.save_bytes("", vec![]).is_err());
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use ulid::Ulid;
+
+use super::{
+    dedup_batch, ChatMessage, ChatRepository, ConflictPolicy, GiftRank, InsertReport,
+    MessageFilter, Order, OrderChanges, OrderFilter, OrderRepository, OrdersPerPeriod,
+    PercentileGroup, QuantityPercentiles, Region, RegionRepository, RegionShare, RegionSort,
+    RegionsOrdersSummary, RegionsTopGifts, TimeBucket, TopGift, TopGiftsFilter,
+};
+use crate::prelude::*;
+
+#[derive(Debug, Default)]
+struct Tables {
+    orders: BTreeMap<i32, Order>,
+    regions: BTreeMap<i32, Region>,
+    messages: BTreeMap<(u64, Ulid), ChatMessage>,
+}
+
+/// Orders, regions and chat messages kept in memory, mostly useful for tests.
+///
+/// Clones share the same tables.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryRepository {
+    tables: Arc<RwLock<Tables>>,
+}
+
+impl InMemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Tables> {
+        self.tables.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Tables> {
+        self.tables.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Linear interpolation between the closest ranks of sorted values, like
+/// Postgres' `percentile_cont`.
+fn percentile(sorted: &[i32], p: f64) -> f64 {
+    let position = p * (sorted.len() - 1) as f64;
+    let (low, high) = (position.floor() as usize, position.ceil() as usize);
+    let low_value = sorted[low] as f64;
+    low_value + (position - low as f64) * (sorted[high] as f64 - low_value)
+}
+
+/// Total quantity per gift name, sorted by quantity (descending) then name.
+fn rank_gifts<'a>(orders: impl Iterator<Item = &'a Order>) -> Vec<(String, i64)> {
+    let mut gifts = HashMap::<&str, i64>::new();
+    for order in orders {
+        *gifts.entry(order.gift_name.as_str()).or_default() += order.quantity as i64;
+    }
+
+    let mut gifts = gifts
+        .into_iter()
+        .map(|(name, quantity)| (name.to_string(), quantity))
+        .collect::<Vec<_>>();
+    gifts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    gifts
+}
+
+#[async_trait]
+impl OrderRepository for InMemoryRepository {
+    async fn reset(&self) -> Result<()> {
+        self.write().orders.clear();
+        Ok(())
+    }
+
+    async fn insert_many(
+        &self,
+        orders: Vec<Order>,
+        on_conflict: ConflictPolicy,
+    ) -> Result<InsertReport> {
+        let (orders, skipped) = dedup_batch(orders, on_conflict)?;
+        let mut report = InsertReport {
+            skipped,
+            ..Default::default()
+        };
+
+        let now = Utc::now();
+        let mut tables = self.write();
+        if on_conflict == ConflictPolicy::Error {
+            if let Some(order) = orders.iter().find(|o| tables.orders.contains_key(&o.id)) {
+                return Err(AppError::Conflict(f!("order {} already exists", order.id)));
+            }
+        }
+        for mut order in orders {
+            match tables.orders.get(&order.id) {
+                Some(_) if on_conflict == ConflictPolicy::Skip => report.skipped.push(order.id),
+                Some(existing) => {
+                    order.created_at = existing.created_at;
+                    report.updated.push(order.id);
+                    tables.orders.insert(order.id, order);
+                }
+                None => {
+                    order.created_at.get_or_insert(now);
+                    report.inserted.push(order.id);
+                    tables.orders.insert(order.id, order);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn insert(&self, mut order: Order) -> Result<Order> {
+        let mut tables = self.write();
+        if tables.orders.contains_key(&order.id) {
+            return Err(AppError::Conflict(f!("order {} already exists", order.id)));
+        }
+        order.created_at.get_or_insert_with(Utc::now);
+        tables.orders.insert(order.id, order.clone());
+
+        Ok(order)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<Order>> {
+        Ok(self.read().orders.get(&id).cloned())
+    }
+
+    async fn list(&self, filter: OrderFilter) -> Result<Vec<Order>> {
+        let tables = self.read();
+        let orders = tables
+            .orders
+            .values()
+            .filter(|o| filter.after.is_none_or(|after| o.id > after))
+            .filter(|o| filter.region_id.is_none_or(|r| o.region_id == r))
+            .filter(|o| filter.gift_name.as_ref().is_none_or(|g| &o.gift_name == g))
+            .take(filter.limit.max(0) as usize)
+            .cloned()
+            .collect();
+
+        Ok(orders)
+    }
+
+    async fn update(&self, id: i32, changes: OrderChanges) -> Result<Option<Order>> {
+        let mut tables = self.write();
+        let Some(order) = tables.orders.get_mut(&id) else {
+            return Ok(None);
+        };
+        if let Some(region_id) = changes.region_id {
+            order.region_id = region_id;
+        }
+        if let Some(gift_name) = changes.gift_name {
+            order.gift_name = gift_name;
+        }
+        if let Some(quantity) = changes.quantity {
+            order.quantity = quantity;
+        }
+
+        Ok(Some(order.clone()))
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        Ok(self.write().orders.remove(&id).is_some())
+    }
+
+    async fn total_quantity(&self) -> Result<i64> {
+        Ok(self.read().orders.values().map(|o| o.quantity as i64).sum())
+    }
+
+    async fn most_popular_gift(&self) -> Result<Option<String>> {
+        let tables = self.read();
+        Ok(rank_gifts(tables.orders.values())
+            .into_iter()
+            .next()
+            .map(|(name, _)| name))
+    }
+
+    async fn gift_ranking(&self) -> Result<Vec<GiftRank>> {
+        let tables = self.read();
+        let gifts = rank_gifts(tables.orders.values());
+
+        let mut ranking = Vec::<GiftRank>::with_capacity(gifts.len());
+        for (i, (gift_name, quantity)) in gifts.into_iter().enumerate() {
+            let rank = match ranking.last() {
+                Some(previous) if previous.quantity == quantity => previous.rank,
+                _ => i as i64 + 1,
+            };
+            ranking.push(GiftRank {
+                gift_name,
+                quantity,
+                rank,
+            });
+        }
+
+        Ok(ranking)
+    }
+
+    async fn orders_over_time(
+        &self,
+        bucket: TimeBucket,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<OrdersPerPeriod>> {
+        let duration = match bucket {
+            TimeBucket::Hour => Duration::hours(1),
+            TimeBucket::Day => Duration::days(1),
+        };
+
+        let tables = self.read();
+        let mut periods = BTreeMap::<DateTime<Utc>, OrdersPerPeriod>::new();
+        for order in tables.orders.values() {
+            let Some(created_at) = order.created_at else {
+                continue;
+            };
+            if from.is_some_and(|from| created_at < from) || to.is_some_and(|to| created_at >= to) {
+                continue;
+            }
+
+            let period = created_at
+                .duration_trunc(duration)
+                .map_err(|e| AppError::Internal(e.into()))?;
+            let entry = periods.entry(period).or_insert(OrdersPerPeriod {
+                period,
+                orders: 0,
+                quantity: 0,
+            });
+            entry.orders += 1;
+            entry.quantity += order.quantity as i64;
+        }
+
+        Ok(periods.into_values().collect())
+    }
+
+    async fn quantity_percentiles(&self, by: PercentileGroup) -> Result<Vec<QuantityPercentiles>> {
+        let tables = self.read();
+        let mut groups = BTreeMap::<&str, Vec<i32>>::new();
+        for order in tables.orders.values() {
+            let group = match by {
+                PercentileGroup::Gift => order.gift_name.as_str(),
+                PercentileGroup::Region => match tables.regions.get(&order.region_id) {
+                    Some(region) => region.name.as_str(),
+                    None => continue,
+                },
+            };
+            groups.entry(group).or_default().push(order.quantity);
+        }
+
+        Ok(groups
+            .into_iter()
+            .map(|(group, mut quantities)| {
+                quantities.sort_unstable();
+                QuantityPercentiles {
+                    group: group.to_string(),
+                    orders: quantities.len() as i64,
+                    p50: percentile(&quantities, 0.5),
+                    p90: percentile(&quantities, 0.9),
+                    p99: percentile(&quantities, 0.99),
+                }
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl RegionRepository for InMemoryRepository {
+    async fn reset(&self) -> Result<()> {
+        self.write().regions.clear();
+        Ok(())
+    }
+
+    async fn reset_with_orders(&self) -> Result<()> {
+        let mut tables = self.write();
+        tables.orders.clear();
+        tables.regions.clear();
+        Ok(())
+    }
+
+    async fn insert_many(&self, regions: Vec<Region>) -> Result<()> {
+        let mut tables = self.write();
+        if let Some(region) = regions.iter().find(|r| tables.regions.contains_key(&r.id)) {
+            return Err(AppError::Conflict(f!(
+                "region {} already exists",
+                region.id
+            )));
+        }
+        tables
+            .regions
+            .extend(regions.into_iter().map(|r| (r.id, r)));
+
+        Ok(())
+    }
+
+    async fn insert(&self, region: Region) -> Result<Region> {
+        let mut tables = self.write();
+        if tables.regions.contains_key(&region.id) {
+            return Err(AppError::Conflict(f!(
+                "region {} already exists",
+                region.id
+            )));
+        }
+        tables.regions.insert(region.id, region.clone());
+
+        Ok(region)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<Region>> {
+        Ok(self.read().regions.get(&id).cloned())
+    }
+
+    async fn list(&self) -> Result<Vec<Region>> {
+        Ok(self.read().regions.values().cloned().collect())
+    }
+
+    async fn rename(&self, id: i32, name: String) -> Result<Option<Region>> {
+        let mut tables = self.write();
+        let Some(region) = tables.regions.get_mut(&id) else {
+            return Ok(None);
+        };
+        region.name = name;
+
+        Ok(Some(region.clone()))
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        Ok(self.write().regions.remove(&id).is_some())
+    }
+
+    async fn orders_summary(&self) -> Result<Vec<RegionsOrdersSummary>> {
+        let tables = self.read();
+        let mut totals = BTreeMap::<String, i64>::new();
+        for order in tables.orders.values() {
+            if let Some(region) = tables.regions.get(&order.region_id) {
+                *totals.entry(region.name.clone()).or_default() += order.quantity as i64;
+            }
+        }
+
+        Ok(totals
+            .into_iter()
+            .map(|(region, total)| RegionsOrdersSummary { region, total })
+            .collect())
+    }
+
+    async fn region_shares(&self) -> Result<Vec<RegionShare>> {
+        let grand_total = OrderRepository::total_quantity(self).await?;
+        let summary = self.orders_summary().await?;
+
+        Ok(summary
+            .into_iter()
+            .map(|s| RegionShare {
+                share: if grand_total == 0 {
+                    0.0
+                } else {
+                    s.total as f64 / grand_total as f64
+                },
+                region: s.region,
+                total: s.total,
+            })
+            .collect())
+    }
+
+    async fn top_gifts(&self, filter: TopGiftsFilter) -> Result<Vec<RegionsTopGifts>> {
+        let tables = self.read();
+        let mut orders_by_region = BTreeMap::<&str, Vec<&Order>>::new();
+        for region in tables.regions.values() {
+            orders_by_region.entry(region.name.as_str()).or_default();
+        }
+        for order in tables.orders.values() {
+            if let Some(region) = tables.regions.get(&order.region_id) {
+                orders_by_region
+                    .entry(region.name.as_str())
+                    .or_default()
+                    .push(order);
+            }
+        }
+
+        let name_filter = filter.region.map(|r| r.to_lowercase());
+        let mut regions = orders_by_region
+            .into_iter()
+            .filter(|(region, _)| {
+                name_filter
+                    .as_ref()
+                    .is_none_or(|f| region.to_lowercase().contains(f))
+            })
+            .map(|(region, orders)| {
+                let total = orders.iter().map(|o| o.quantity as i64).sum::<i64>();
+                (region, total, orders)
+            })
+            .collect::<Vec<_>>();
+        if filter.sort == RegionSort::Total {
+            regions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        }
+
+        Ok(regions
+            .into_iter()
+            .filter(|(region, total, _)| match (&filter.after, filter.sort) {
+                (None, _) => true,
+                (Some(after), RegionSort::Name) => *region > after.region.as_str(),
+                (Some(after), RegionSort::Total) => {
+                    *total < after.total
+                        || (*total == after.total && *region > after.region.as_str())
+                }
+            })
+            .take(filter.limit.map_or(usize::MAX, |l| l.max(0) as usize))
+            .map(|(region, total, orders)| RegionsTopGifts {
+                region: region.to_string(),
+                total: Some(total),
+                top_gifts: rank_gifts(orders.into_iter())
+                    .into_iter()
+                    .filter(|(_, quantity)| filter.min_quantity.is_none_or(|min| *quantity >= min))
+                    .take(filter.number as usize)
+                    .map(|(gift_name, quantity)| TopGift::WithQuantity {
+                        gift_name,
+                        quantity,
+                    })
+                    .collect(),
+            })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ChatRepository for InMemoryRepository {
+    async fn insert_message(&self, message: &ChatMessage) -> Result<()> {
+        self.write()
+            .messages
+            .insert((message.room, message.id), message.clone());
+        Ok(())
+    }
+
+    async fn messages(&self, room: u64, filter: MessageFilter) -> Result<Vec<ChatMessage>> {
+        let start = filter
+            .after
+            .map_or(Bound::Unbounded, |after| Bound::Excluded((room, after)));
+        let end = Bound::Included((room, Ulid::from(u128::MAX)));
+
+        Ok(self
+            .read()
+            .messages
+            .range((start, end))
+            .filter(|((r, _), _)| *r == room)
+            .take(filter.limit.max(0) as usize)
+            .map(|(_, m)| m.clone())
+            .collect())
+    }
+}
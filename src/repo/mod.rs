@@ -0,0 +1,410 @@
+mod memory;
+mod postgres;
+
+pub use memory::InMemoryRepository;
+pub use postgres::PgRepository;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{migrate::MigrateError, FromRow, PgPool};
+use ulid::{Generator, Ulid};
+
+use crate::prelude::*;
+
+/// Apply the pending migrations from the `migrations/` directory.
+pub async fn run_migrations(db: &PgPool) -> core::result::Result<(), MigrateError> {
+    sqlx::migrate!().run(db).await
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct Order {
+    pub id: i32,
+    pub region_id: i32,
+    pub gift_name: String,
+    pub quantity: i32,
+    /// Defaults to the time the order is inserted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// Size of the `orders.gift_name` column.
+pub const GIFT_NAME_MAX_CHARS: usize = 50;
+
+impl Order {
+    /// Check the order fits the `orders` table before sending it to the database.
+    pub fn validate(&self) -> Result<()> {
+        if self.quantity < 0 {
+            return Err(AppError::BadRequest(f!(
+                "order {}: quantity must not be negative, got {}",
+                self.id,
+                self.quantity
+            )));
+        }
+        if self.gift_name.chars().count() > GIFT_NAME_MAX_CHARS {
+            return Err(AppError::BadRequest(f!(
+                "order {}: gift_name must be at most {GIFT_NAME_MAX_CHARS} characters",
+                self.id
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// The order with `changes` applied.
+    pub fn with_changes(self, changes: &OrderChanges) -> Self {
+        Self {
+            region_id: changes.region_id.unwrap_or(self.region_id),
+            gift_name: changes.gift_name.clone().unwrap_or(self.gift_name),
+            quantity: changes.quantity.unwrap_or(self.quantity),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct Region {
+    pub id: i32,
+    pub name: String,
+}
+
+/// Size of the `regions.name` column.
+pub const REGION_NAME_MAX_CHARS: usize = 50;
+
+impl Region {
+    /// Check the region fits the `regions` table before sending it to the database.
+    pub fn validate(&self) -> Result<()> {
+        validate_region_name(self.id, &self.name)
+    }
+}
+
+/// Check a name fits the `regions.name` column.
+pub fn validate_region_name(id: i32, name: &str) -> Result<()> {
+    if name.is_empty() {
+        return Err(AppError::BadRequest(f!(
+            "region {id}: name must not be empty"
+        )));
+    }
+    if name.chars().count() > REGION_NAME_MAX_CHARS {
+        return Err(AppError::BadRequest(f!(
+            "region {id}: name must be at most {REGION_NAME_MAX_CHARS} characters"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Filters and keyset pagination for listing orders.
+#[derive(Debug, Clone, Default)]
+pub struct OrderFilter {
+    pub region_id: Option<i32>,
+    pub gift_name: Option<String>,
+    /// Only return orders with an id greater than this one.
+    pub after: Option<i32>,
+    pub limit: i64,
+}
+
+/// Fields to change on an existing order; `None` keeps the current value.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct OrderChanges {
+    pub region_id: Option<i32>,
+    pub gift_name: Option<String>,
+    pub quantity: Option<i32>,
+}
+
+/// What to do when an order id already exists.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    /// Reject the whole batch.
+    #[default]
+    Error,
+    /// Keep the existing order and ignore the new one.
+    Skip,
+    /// Replace the existing order with the new one.
+    Update,
+}
+
+/// Outcome of a bulk insert, by order id.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct InsertReport {
+    pub inserted: Vec<i32>,
+    pub updated: Vec<i32>,
+    pub skipped: Vec<i32>,
+}
+
+/// Resolve duplicate ids inside one batch according to `policy`.
+///
+/// Returns the orders to write, in their original order, and the ids
+/// skipped because they appeared earlier in the batch.
+fn dedup_batch(orders: Vec<Order>, policy: ConflictPolicy) -> Result<(Vec<Order>, Vec<i32>)> {
+    let mut positions = HashMap::<i32, usize>::new();
+    let mut batch = Vec::<Order>::with_capacity(orders.len());
+    let mut skipped = Vec::new();
+
+    for order in orders {
+        match (positions.get(&order.id), policy) {
+            (None, _) => {
+                positions.insert(order.id, batch.len());
+                batch.push(order);
+            }
+            (Some(_), ConflictPolicy::Error) => {
+                return Err(AppError::Conflict(f!(
+                    "order {} appears more than once",
+                    order.id
+                )));
+            }
+            (Some(_), ConflictPolicy::Skip) => skipped.push(order.id),
+            (Some(&i), ConflictPolicy::Update) => batch[i] = order,
+        }
+    }
+
+    Ok((batch, skipped))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct RegionsOrdersSummary {
+    pub region: String,
+    pub total: i64,
+}
+
+/// How regions are ordered in the top gifts report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionSort {
+    /// By region name, ascending.
+    #[default]
+    Name,
+    /// By total quantity ordered, descending, then by name.
+    Total,
+}
+
+/// The last region of a page of the top gifts report.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RegionCursor {
+    pub region: String,
+    pub total: i64,
+}
+
+/// Filters, ordering and pagination for the top gifts report.
+#[derive(Debug, Clone, Default)]
+pub struct TopGiftsFilter {
+    /// How many gifts to list per region.
+    pub number: u32,
+    /// Only regions whose name contains this, ignoring case.
+    pub region: Option<String>,
+    /// Leave out gifts with a lower total quantity.
+    pub min_quantity: Option<i64>,
+    pub sort: RegionSort,
+    /// Only return regions sorted after this one.
+    pub after: Option<RegionCursor>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum TopGift {
+    Name(String),
+    WithQuantity { gift_name: String, quantity: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegionsTopGifts {
+    pub region: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+    pub top_gifts: Vec<TopGift>,
+}
+
+impl RegionsTopGifts {
+    /// Keep only the gift names, as in the original report.
+    pub fn without_quantities(self) -> Self {
+        Self {
+            region: self.region,
+            total: None,
+            top_gifts: self
+                .top_gifts
+                .into_iter()
+                .map(|gift| match gift {
+                    TopGift::WithQuantity { gift_name, .. } => TopGift::Name(gift_name),
+                    name => name,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn cursor(&self) -> RegionCursor {
+        RegionCursor {
+            region: self.region.clone(),
+            total: self.total.unwrap_or_default(),
+        }
+    }
+}
+
+/// Total quantity of a gift and its rank, where equal quantities share a rank.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct GiftRank {
+    pub gift_name: String,
+    pub quantity: i64,
+    pub rank: i64,
+}
+
+/// Total quantity ordered in a region and its share of all the orders.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct RegionShare {
+    pub region: String,
+    pub total: i64,
+    pub share: f64,
+}
+
+/// What order quantities are grouped by when computing percentiles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PercentileGroup {
+    #[default]
+    Gift,
+    Region,
+}
+
+/// Percentiles of the quantity of the orders of one gift or region,
+/// interpolated like Postgres' `percentile_cont`.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct QuantityPercentiles {
+    pub group: String,
+    pub orders: i64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeBucket {
+    Hour,
+    Day,
+}
+
+impl TimeBucket {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimeBucket::Hour => "hour",
+            TimeBucket::Day => "day",
+        }
+    }
+}
+
+/// Orders created within one time bucket, starting at `period` (UTC).
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct OrdersPerPeriod {
+    pub period: DateTime<Utc>,
+    pub orders: i64,
+    pub quantity: i64,
+}
+
+/// A message sent to a chat room.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChatMessage {
+    /// Also orders the messages of a room by time.
+    pub id: Ulid,
+    pub room: u64,
+    pub user: String,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ChatMessage {
+    pub fn new(room: u64, user: String, message: String) -> Self {
+        // ids generated within the same millisecond still increase
+        static IDS: LazyLock<Mutex<Generator>> = LazyLock::new(|| Mutex::new(Generator::new()));
+        let id = IDS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .generate()
+            .unwrap_or_else(|_| Ulid::new());
+        Self {
+            created_at: DateTime::<Utc>::from(id.datetime()),
+            id,
+            room,
+            user,
+            message,
+        }
+    }
+}
+
+/// Keyset pagination over the messages of one room, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    /// Only return messages sent after this one.
+    pub after: Option<Ulid>,
+    pub limit: i64,
+}
+
+#[async_trait]
+pub trait OrderRepository: Send + Sync {
+    /// Remove every order, keeping the schema.
+    async fn reset(&self) -> Result<()>;
+    async fn insert_many(
+        &self,
+        orders: Vec<Order>,
+        on_conflict: ConflictPolicy,
+    ) -> Result<InsertReport>;
+    async fn insert(&self, order: Order) -> Result<Order>;
+    async fn get(&self, id: i32) -> Result<Option<Order>>;
+    async fn list(&self, filter: OrderFilter) -> Result<Vec<Order>>;
+    async fn update(&self, id: i32, changes: OrderChanges) -> Result<Option<Order>>;
+    /// Returns `false` if there was no order with this id.
+    async fn delete(&self, id: i32) -> Result<bool>;
+
+    /// Sum of the quantity of all orders.
+    async fn total_quantity(&self) -> Result<i64>;
+    /// The gift with the highest total quantity, if there are any orders.
+    async fn most_popular_gift(&self) -> Result<Option<String>>;
+    /// Every gift with its total quantity, ranked from the most ordered.
+    async fn gift_ranking(&self) -> Result<Vec<GiftRank>>;
+    /// Orders created in `[from, to)`, grouped by `bucket`.
+    async fn orders_over_time(
+        &self,
+        bucket: TimeBucket,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<OrdersPerPeriod>>;
+    /// Quantity percentiles per gift name or region name, sorted by name.
+    async fn quantity_percentiles(&self, by: PercentileGroup) -> Result<Vec<QuantityPercentiles>>;
+}
+
+#[async_trait]
+pub trait RegionRepository: Send + Sync {
+    /// Remove every region, keeping the schema.
+    async fn reset(&self) -> Result<()>;
+    /// Remove every region and every order at once, keeping the schema.
+    async fn reset_with_orders(&self) -> Result<()>;
+    async fn insert_many(&self, regions: Vec<Region>) -> Result<()>;
+    async fn insert(&self, region: Region) -> Result<Region>;
+    async fn get(&self, id: i32) -> Result<Option<Region>>;
+    async fn list(&self) -> Result<Vec<Region>>;
+    async fn rename(&self, id: i32, name: String) -> Result<Option<Region>>;
+    /// Returns `false` if there was no region with this id.
+    async fn delete(&self, id: i32) -> Result<bool>;
+
+    /// Total quantity ordered per region name, for regions with orders.
+    async fn orders_summary(&self) -> Result<Vec<RegionsOrdersSummary>>;
+    /// Total quantity and share of all orders per region name.
+    async fn region_shares(&self) -> Result<Vec<RegionShare>>;
+    /// The most ordered gifts of every region matching `filter`, with quantities.
+    async fn top_gifts(&self, filter: TopGiftsFilter) -> Result<Vec<RegionsTopGifts>>;
+}
+
+#[async_trait]
+pub trait ChatRepository: Send + Sync {
+    async fn insert_message(&self, message: &ChatMessage) -> Result<()>;
+    async fn messages(&self, room: u64, filter: MessageFilter) -> Result<Vec<ChatMessage>>;
+}
+
+pub type DynOrderRepository = Arc<dyn OrderRepository>;
+pub type DynRegionRepository = Arc<dyn RegionRepository>;
+pub type DynChatRepository = Arc<dyn ChatRepository>;
@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, QueryBuilder};
+use ulid::Ulid;
+
+use super::{
+    dedup_batch, ChatMessage, ChatRepository, ConflictPolicy, GiftRank, InsertReport,
+    MessageFilter, Order, OrderChanges, OrderFilter, OrderRepository, OrdersPerPeriod,
+    PercentileGroup, QuantityPercentiles, Region, RegionRepository, RegionShare, RegionSort,
+    RegionsOrdersSummary, RegionsTopGifts, TimeBucket, TopGift, TopGiftsFilter,
+};
+use crate::prelude::*;
+
+/// Orders, regions and chat messages stored in Postgres.
+#[derive(Debug, Clone)]
+pub struct PgRepository {
+    db: PgPool,
+}
+
+impl PgRepository {
+    pub fn new(db: PgPool) -> Self {
+        Self { db }
+    }
+}
+
+#[async_trait]
+impl OrderRepository for PgRepository {
+    async fn reset(&self) -> Result<()> {
+        sqlx::query("TRUNCATE TABLE orders")
+            .execute(&self.db)
+            .await
+            .context("truncate orders table")?;
+
+        Ok(())
+    }
+
+    async fn insert_many(
+        &self,
+        orders: Vec<Order>,
+        on_conflict: ConflictPolicy,
+    ) -> Result<InsertReport> {
+        let (orders, skipped) = dedup_batch(orders, on_conflict)?;
+        let mut report = InsertReport {
+            skipped,
+            ..Default::default()
+        };
+        if orders.is_empty() {
+            return Ok(report);
+        }
+        let ids = orders.iter().map(|o| o.id).collect::<Vec<_>>();
+
+        let mut query_builder = QueryBuilder::<Postgres>::new(
+            "INSERT INTO orders (id, region_id, gift_name, quantity, created_at)",
+        );
+        let now = Utc::now();
+        query_builder.push_values(orders, |mut b, order| {
+            b.push_bind(order.id);
+            b.push_bind(order.region_id);
+            b.push_bind(order.gift_name);
+            b.push_bind(order.quantity);
+            b.push_bind(order.created_at.unwrap_or(now));
+        });
+        query_builder.push(match on_conflict {
+            ConflictPolicy::Error => "",
+            ConflictPolicy::Skip => " ON CONFLICT (id) DO NOTHING",
+            ConflictPolicy::Update => {
+                " ON CONFLICT (id) DO UPDATE SET
+                    region_id = EXCLUDED.region_id,
+                    gift_name = EXCLUDED.gift_name,
+                    quantity = EXCLUDED.quantity"
+            }
+        });
+        // `xmax` is only zero for freshly inserted rows
+        query_builder.push(" RETURNING id, (xmax = 0) AS inserted");
+        tracing::debug!("Query: {}", query_builder.sql());
+
+        let rows = query_builder
+            .build_query_as::<(i32, bool)>()
+            .fetch_all(&self.db)
+            .await
+            .map_err(|e| AppError::on_unique_violation(e, "some orders already exist"))?;
+
+        let written = rows.iter().map(|(id, _)| *id).collect::<HashSet<_>>();
+        for (id, inserted) in rows {
+            if inserted {
+                report.inserted.push(id);
+            } else {
+                report.updated.push(id);
+            }
+        }
+        report
+            .skipped
+            .extend(ids.into_iter().filter(|id| !written.contains(id)));
+
+        Ok(report)
+    }
+
+    async fn insert(&self, order: Order) -> Result<Order> {
+        let id = order.id;
+        sqlx::query_as::<_, Order>(
+            "INSERT INTO orders (id, region_id, gift_name, quantity, created_at)
+             VALUES ($1, $2, $3, $4, COALESCE($5, now()))
+             RETURNING id, region_id, gift_name, quantity, created_at",
+        )
+        .bind(order.id)
+        .bind(order.region_id)
+        .bind(order.gift_name)
+        .bind(order.quantity)
+        .bind(order.created_at)
+        .fetch_one(&self.db)
+        .await
+        .map_err(|e| AppError::on_unique_violation(e, f!("order {id} already exists")))
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<Order>> {
+        let order = sqlx::query_as::<_, Order>(
+            "SELECT id, region_id, gift_name, quantity, created_at FROM orders WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(order)
+    }
+
+    async fn list(&self, filter: OrderFilter) -> Result<Vec<Order>> {
+        let mut query_builder = QueryBuilder::<Postgres>::new(
+            "SELECT id, region_id, gift_name, quantity, created_at FROM orders WHERE TRUE",
+        );
+        if let Some(region_id) = filter.region_id {
+            query_builder.push(" AND region_id = ").push_bind(region_id);
+        }
+        if let Some(gift_name) = filter.gift_name {
+            query_builder.push(" AND gift_name = ").push_bind(gift_name);
+        }
+        if let Some(after) = filter.after {
+            query_builder.push(" AND id > ").push_bind(after);
+        }
+        query_builder
+            .push(" ORDER BY id LIMIT ")
+            .push_bind(filter.limit);
+        tracing::debug!("Query: {}", query_builder.sql());
+
+        let orders = query_builder
+            .build_query_as::<Order>()
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(orders)
+    }
+
+    async fn update(&self, id: i32, changes: OrderChanges) -> Result<Option<Order>> {
+        let order = sqlx::query_as::<_, Order>(
+            "UPDATE orders SET
+               region_id = COALESCE($2, region_id),
+               gift_name = COALESCE($3, gift_name),
+               quantity = COALESCE($4, quantity)
+             WHERE id = $1
+             RETURNING id, region_id, gift_name, quantity, created_at",
+        )
+        .bind(id)
+        .bind(changes.region_id)
+        .bind(changes.gift_name)
+        .bind(changes.quantity)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(order)
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM orders WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn total_quantity(&self) -> Result<i64> {
+        let (total,) = sqlx::query_as::<_, (i64,)>("SELECT COALESCE(SUM(quantity), 0) FROM orders")
+            .fetch_one(&self.db)
+            .await?;
+
+        Ok(total)
+    }
+
+    async fn most_popular_gift(&self) -> Result<Option<String>> {
+        let result = sqlx::query_as::<_, (String,)>(
+            "SELECT gift_name, SUM(quantity) AS quantity FROM orders
+             GROUP BY gift_name ORDER BY quantity DESC, gift_name LIMIT 1",
+        )
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(result.map(|(name,)| name))
+    }
+
+    async fn gift_ranking(&self) -> Result<Vec<GiftRank>> {
+        let data = sqlx::query_as::<_, GiftRank>(
+            "SELECT
+               gift_name,
+               SUM(quantity) AS quantity,
+               RANK() OVER (ORDER BY SUM(quantity) DESC) AS rank
+             FROM orders
+             GROUP BY gift_name
+             ORDER BY rank, gift_name",
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(data)
+    }
+
+    async fn orders_over_time(
+        &self,
+        bucket: TimeBucket,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Result<Vec<OrdersPerPeriod>> {
+        let data = sqlx::query_as::<_, OrdersPerPeriod>(
+            "SELECT
+               date_trunc($1, created_at AT TIME ZONE 'UTC') AT TIME ZONE 'UTC' AS period,
+               COUNT(*) AS orders,
+               COALESCE(SUM(quantity), 0) AS quantity
+             FROM orders
+             WHERE ($2::TIMESTAMPTZ IS NULL OR created_at >= $2)
+               AND ($3::TIMESTAMPTZ IS NULL OR created_at < $3)
+             GROUP BY 1
+             ORDER BY 1",
+        )
+        .bind(bucket.as_str())
+        .bind(from)
+        .bind(to)
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(data)
+    }
+
+    async fn quantity_percentiles(&self, by: PercentileGroup) -> Result<Vec<QuantityPercentiles>> {
+        let (group, from) = match by {
+            PercentileGroup::Gift => ("orders.gift_name", "orders"),
+            PercentileGroup::Region => (
+                "regions.name",
+                "orders INNER JOIN regions ON orders.region_id = regions.id",
+            ),
+        };
+        let data = sqlx::query_as::<_, QuantityPercentiles>(&f!(r#"SELECT
+               {group} AS "group",
+               COUNT(*) AS orders,
+               percentile_cont(0.5) WITHIN GROUP (ORDER BY orders.quantity) AS p50,
+               percentile_cont(0.9) WITHIN GROUP (ORDER BY orders.quantity) AS p90,
+               percentile_cont(0.99) WITHIN GROUP (ORDER BY orders.quantity) AS p99
+             FROM {from}
+             GROUP BY 1
+             ORDER BY 1"#))
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl RegionRepository for PgRepository {
+    async fn reset(&self) -> Result<()> {
+        sqlx::query("TRUNCATE TABLE regions")
+            .execute(&self.db)
+            .await
+            .context("truncate regions table")?;
+
+        Ok(())
+    }
+
+    async fn reset_with_orders(&self) -> Result<()> {
+        sqlx::query("TRUNCATE TABLE orders, regions")
+            .execute(&self.db)
+            .await
+            .context("truncate orders and regions tables")?;
+
+        Ok(())
+    }
+
+    async fn insert_many(&self, regions: Vec<Region>) -> Result<()> {
+        if regions.is_empty() {
+            return Ok(());
+        }
+
+        let mut query_builder = QueryBuilder::<Postgres>::new("INSERT INTO regions (id, name)");
+        query_builder.push_values(regions, |mut b, region| {
+            b.push_bind(region.id);
+            b.push_bind(region.name);
+        });
+        tracing::debug!("Query: {}", query_builder.sql());
+
+        query_builder.build().execute(&self.db).await?;
+
+        Ok(())
+    }
+
+    async fn insert(&self, region: Region) -> Result<Region> {
+        let id = region.id;
+        sqlx::query_as::<_, Region>(
+            "INSERT INTO regions (id, name) VALUES ($1, $2) RETURNING id, name",
+        )
+        .bind(region.id)
+        .bind(region.name)
+        .fetch_one(&self.db)
+        .await
+        .map_err(|e| AppError::on_unique_violation(e, f!("region {id} already exists")))
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<Region>> {
+        let region = sqlx::query_as::<_, Region>("SELECT id, name FROM regions WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+
+        Ok(region)
+    }
+
+    async fn list(&self) -> Result<Vec<Region>> {
+        let regions = sqlx::query_as::<_, Region>("SELECT id, name FROM regions ORDER BY id")
+            .fetch_all(&self.db)
+            .await?;
+
+        Ok(regions)
+    }
+
+    async fn rename(&self, id: i32, name: String) -> Result<Option<Region>> {
+        let region = sqlx::query_as::<_, Region>(
+            "UPDATE regions SET name = $2 WHERE id = $1 RETURNING id, name",
+        )
+        .bind(id)
+        .bind(name)
+        .fetch_optional(&self.db)
+        .await?;
+
+        Ok(region)
+    }
+
+    async fn delete(&self, id: i32) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM regions WHERE id = $1")
+            .bind(id)
+            .execute(&self.db)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn orders_summary(&self) -> Result<Vec<RegionsOrdersSummary>> {
+        let data = sqlx::query_as::<_, RegionsOrdersSummary>(
+            r#"
+    SELECT regions.name AS region, SUM(orders.quantity) AS total
+    FROM orders INNER JOIN regions ON orders.region_id = regions.id
+    GROUP BY 1
+    ORDER BY 1"#,
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(data)
+    }
+
+    async fn region_shares(&self) -> Result<Vec<RegionShare>> {
+        let data = sqlx::query_as::<_, RegionShare>(
+            r#"
+    SELECT
+      regions.name AS region,
+      SUM(orders.quantity) AS total,
+      COALESCE(SUM(orders.quantity)::FLOAT8 / NULLIF((SELECT SUM(quantity) FROM orders), 0), 0) AS share
+    FROM orders INNER JOIN regions ON orders.region_id = regions.id
+    GROUP BY 1
+    ORDER BY 1"#,
+        )
+        .fetch_all(&self.db)
+        .await?;
+
+        Ok(data)
+    }
+
+    async fn top_gifts(&self, filter: TopGiftsFilter) -> Result<Vec<RegionsTopGifts>> {
+        // names are compared with the "C" collation so pages follow the byte order of the cursor
+        let mut query_builder = QueryBuilder::<Postgres>::new(
+            r#"
+SELECT regions.name AS region, COALESCE(SUM(orders.quantity), 0) AS total
+FROM regions LEFT JOIN orders ON orders.region_id = regions.id
+WHERE TRUE"#,
+        );
+        if let Some(region) = filter.region {
+            query_builder
+                .push(" AND strpos(lower(regions.name), lower(")
+                .push_bind(region)
+                .push(")) > 0");
+        }
+        match (&filter.after, filter.sort) {
+            (None, _) => {
+                query_builder.push(" GROUP BY 1");
+            }
+            (Some(after), RegionSort::Name) => {
+                query_builder
+                    .push(r#" AND regions.name COLLATE "C" > "#)
+                    .push_bind(after.region.clone())
+                    .push(" GROUP BY 1");
+            }
+            (Some(after), RegionSort::Total) => {
+                query_builder
+                    .push(" GROUP BY 1 HAVING COALESCE(SUM(orders.quantity), 0) < ")
+                    .push_bind(after.total)
+                    .push(" OR (COALESCE(SUM(orders.quantity), 0) = ")
+                    .push_bind(after.total)
+                    .push(r#" AND regions.name COLLATE "C" > "#)
+                    .push_bind(after.region.clone())
+                    .push(")");
+            }
+        }
+        query_builder.push(match filter.sort {
+            RegionSort::Name => r#" ORDER BY regions.name COLLATE "C""#,
+            RegionSort::Total => r#" ORDER BY 2 DESC, regions.name COLLATE "C""#,
+        });
+        if let Some(limit) = filter.limit {
+            query_builder.push(" LIMIT ").push_bind(limit);
+        }
+        tracing::debug!("Query: {}", query_builder.sql());
+
+        let regions = query_builder
+            .build_query_as::<(String, i64)>()
+            .fetch_all(&self.db)
+            .await?;
+        if regions.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut top_gifts = BTreeMap::<String, Vec<TopGift>>::new();
+        if filter.number > 0 {
+            let names = regions.iter().map(|(r, _)| r.clone()).collect::<Vec<_>>();
+            let data = sqlx::query_as::<_, (String, String, i64)>(
+                r#"
+WITH added_row_number AS (
+  SELECT
+    regions.name AS region,
+    orders.gift_name AS gift_name,
+    SUM(orders.quantity) AS quantity,
+    ROW_NUMBER() OVER(PARTITION BY regions.name ORDER BY SUM(orders.quantity) DESC, orders.gift_name ASC) AS row_number
+  FROM orders INNER JOIN regions ON orders.region_id = regions.id
+  WHERE regions.name = ANY($1)
+  GROUP BY 1, 2
+  HAVING $2::INT8 IS NULL OR SUM(orders.quantity) >= $2
+)
+SELECT region, gift_name, quantity FROM added_row_number WHERE row_number <= $3 ORDER BY region, row_number;
+            "#,
+            )
+            .bind(names)
+            .bind(filter.min_quantity)
+            .bind(filter.number as i64)
+            .fetch_all(&self.db)
+            .await?;
+            tracing::debug!("Top gifts of Regions (raw data): {:?}", data);
+
+            for (region, gift_name, quantity) in data {
+                top_gifts
+                    .entry(region)
+                    .or_default()
+                    .push(TopGift::WithQuantity {
+                        gift_name,
+                        quantity,
+                    });
+            }
+        }
+
+        Ok(regions
+            .into_iter()
+            .map(|(region, total)| RegionsTopGifts {
+                top_gifts: top_gifts.remove(&region).unwrap_or_default(),
+                total: Some(total),
+                region,
+            })
+            .collect())
+    }
+}
+
+/// Room ids are `BIGINT`, only the ids fitting in an `i64` can be stored.
+fn room_id(room: u64) -> Result<i64> {
+    i64::try_from(room).map_err(|_| AppError::BadRequest(f!("room id {room} is too large")))
+}
+
+#[async_trait]
+impl ChatRepository for PgRepository {
+    async fn insert_message(&self, message: &ChatMessage) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO chat_messages (id, room_id, user_name, message, created_at)
+             VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(message.id.to_string())
+        .bind(room_id(message.room)?)
+        .bind(&message.user)
+        .bind(&message.message)
+        .bind(message.created_at)
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn messages(&self, room: u64, filter: MessageFilter) -> Result<Vec<ChatMessage>> {
+        let rows = sqlx::query_as::<_, (String, String, String, DateTime<Utc>)>(
+            "SELECT id, user_name, message, created_at FROM chat_messages
+             WHERE room_id = $1 AND ($2::TEXT IS NULL OR id > $2)
+             ORDER BY id
+             LIMIT $3",
+        )
+        .bind(room_id(room)?)
+        .bind(filter.after.map(|id| id.to_string()))
+        .bind(filter.limit)
+        .fetch_all(&self.db)
+        .await?;
+
+        rows.into_iter()
+            .map(|(id, user, message, created_at)| {
+                let id = Ulid::from_string(&id).with_context(|| f!("invalid message id {id:?}"))?;
+                Ok(ChatMessage {
+                    id,
+                    room,
+                    user,
+                    message,
+                    created_at,
+                })
+            })
+            .collect()
+    }
+}
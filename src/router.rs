@@ -0,0 +1,182 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware,
+    routing::{get, post},
+    Router,
+};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    services::ServeDir,
+    trace::TraceLayer,
+};
+
+use crate::{app_state::AppState, archive, auth, errors, handlers};
+
+/// Build the application router with all the routes of the challenges.
+///
+/// Shared by the Shuttle entry point and the standalone binary.
+pub fn build_router(app_state: AppState) -> Router {
+    let limit_upload = middleware::from_fn_with_state(app_state.clone(), archive::limit_upload);
+
+    Router::new()
+        .route("/", get(handlers::hello_world))
+        .route("/-1/error", get(handlers::fake_error))
+        .route("/1/*rest", get(handlers::recalibrate_packet_id))
+        .route("/4/strength", post(handlers::reindeer_strength))
+        .route("/4/contest", post(handlers::reindeer_contest))
+        .route("/5", post(handlers::slice_the_loop))
+        .route("/6", post(handlers::count_elf))
+        .route("/7/decode", get(handlers::cookies_recipe))
+        .route("/7/bake", get(handlers::bake_cookies))
+        .route("/8/weight/:number", get(handlers::get_pokemon_weight))
+        .route("/8/drop/:number", get(handlers::drop_pokemon))
+        .nest_service("/11/assets/", ServeDir::new("assets"))
+        .route("/11/red_pixels", post(handlers::red_pixels))
+        .route("/12/save/:time_key", post(handlers::persist_time))
+        .route("/12/load/:time_key", get(handlers::load_time))
+        .route("/12/ulids", post(handlers::convert_ulids_to_uuids))
+        .route("/12/ulids/:weekday", post(handlers::count_ulids))
+        .route("/13/sql", get(handlers::db_health_check))
+        .route("/13/reset", post(handlers::reset_orders_db))
+        .route("/13/orders", post(handlers::create_orders))
+        .route("/13/orders/total", get(handlers::get_total_orders))
+        .route("/13/orders/popular", get(handlers::get_popular_gift))
+        .route("/14/unsafe", post(handlers::render_unsafe_html))
+        .route("/14/safe", post(handlers::render_safe_html))
+        .route("/15/nice", post(handlers::password_validator))
+        .route("/15/game", post(handlers::password_game_validator))
+        .route("/18/reset", post(handlers::reset_orders_and_regions_db))
+        .route("/18/orders", post(handlers::create_orders))
+        .route("/18/regions", post(handlers::create_regions))
+        .route(
+            "/18/regions/total",
+            get(handlers::get_regions_orders_summary),
+        )
+        .route(
+            "/18/regions/top_list/:number",
+            get(handlers::get_regions_top_gifts),
+        )
+        .route("/19/ws/ping", get(handlers::ws_handler))
+        .route("/19/ws/game", get(handlers::game_socket))
+        .route("/19/game/leaderboard", get(handlers::get_leaderboard))
+        .route("/19/ws/room/:room_id/user/:user", get(handlers::chatroom))
+        .route("/19/rooms", get(handlers::get_rooms_metrics))
+        .route(
+            "/19/rooms/:room_id/members",
+            get(handlers::get_room_members),
+        )
+        .route(
+            "/19/rooms/:room_id/messages",
+            get(handlers::list_room_messages).post(handlers::post_room_message),
+        )
+        .route("/19/rooms/:room_id/events", get(handlers::room_events))
+        .route(
+            "/19/rooms/:room_id/members/:user/mute",
+            post(handlers::mute_member).delete(handlers::unmute_member),
+        )
+        .route(
+            "/19/rooms/:room_id/members/:user/kick",
+            post(handlers::kick_member),
+        )
+        .route("/19/views", get(handlers::get_tweet_view_count))
+        .route("/19/reset", post(handlers::reset_tweet_view_count))
+        .route(
+            "/20/archive_files",
+            post(handlers::count_archive_files).layer(limit_upload.clone()),
+        )
+        .route(
+            "/20/archive_files_size",
+            post(handlers::count_archive_files_size).layer(limit_upload.clone()),
+        )
+        .route(
+            "/20/cookie",
+            post(handlers::get_cookie_from_archive_file).layer(limit_upload.clone()),
+        )
+        .route(
+            "/20/archive/manifest",
+            post(handlers::inspect_archive).layer(limit_upload.clone()),
+        )
+        .route(
+            "/20/search",
+            post(handlers::search_repository).layer(limit_upload.clone()),
+        )
+        .route(
+            "/20/analytics",
+            post(handlers::analyze_repository).layer(limit_upload),
+        )
+        .route("/21/coords/:cell_id", get(handlers::parse_coords))
+        .route(
+            "/21/country/:cell_id",
+            get(handlers::get_country_from_coords),
+        )
+        .route("/22/integers", post(handlers::get_gift_emojis))
+        .route("/22/rocket", post(handlers::rocket))
+        .route(
+            "/orders",
+            get(handlers::list_orders).post(handlers::create_order),
+        )
+        .route("/orders/import", post(handlers::import_orders))
+        .route("/orders/analytics/gifts", get(handlers::get_gift_ranking))
+        .route("/orders/analytics/top", get(handlers::get_top_gifts))
+        .route(
+            "/orders/analytics/regions",
+            get(handlers::get_region_shares),
+        )
+        .route(
+            "/orders/analytics/timeseries",
+            get(handlers::get_orders_over_time),
+        )
+        .route(
+            "/orders/analytics/percentiles",
+            get(handlers::get_quantity_percentiles),
+        )
+        .route(
+            "/orders/:id",
+            get(handlers::get_order)
+                .put(handlers::update_order)
+                .patch(handlers::patch_order)
+                .delete(handlers::delete_order),
+        )
+        .route(
+            "/regions",
+            get(handlers::list_regions).post(handlers::create_region),
+        )
+        .route(
+            "/regions/:id",
+            get(handlers::get_region)
+                .put(handlers::update_region)
+                .patch(handlers::patch_region)
+                .delete(handlers::delete_region),
+        )
+        .fallback(handlers::not_found_handler)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::authenticate,
+        ))
+        .with_state(app_state)
+        .layer(middleware::from_fn(errors::render_errors))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
+                // Log the matched route's path (with placeholders not filled in).
+                // Use request.uri() or OriginalUri if you want the real path.
+                let matched_path = request
+                    .extensions()
+                    .get::<MatchedPath>()
+                    .map(MatchedPath::as_str);
+                let request_id = request
+                    .headers()
+                    .get(errors::REQUEST_ID_HEADER)
+                    .and_then(|v| v.to_str().ok());
+
+                tracing::info_span!(
+                    "http_request",
+                    method = ?request.method(),
+                    matched_path,
+                    request_id,
+                    some_other_field = tracing::field::Empty,
+                )
+            }),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
@@ -0,0 +1,72 @@
+use std::{future::IntoFuture, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use tokio::net::TcpListener;
+
+use crate::{app_state::AppState, build_router, chatroom::views::ViewCounts, ws::WsShutdown};
+
+/// How long open WebSocket sessions get to close on shutdown.
+const WS_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the app until Ctrl+C or SIGTERM, then close the WebSocket sessions
+/// and persist the view counts.
+pub async fn serve(listener: TcpListener, app_state: AppState) -> anyhow::Result<()> {
+    let stop = StopGuard {
+        ws_shutdown: app_state.ws_shutdown.clone(),
+        views: app_state.views.clone(),
+    };
+
+    tokio::select! {
+        served = axum::serve(listener, build_router(app_state)).into_future() => served.context("serve")?,
+        // connections run in their own tasks, so sessions can still close
+        // cleanly once the server stops accepting new ones
+        _ = shutdown_signal() => stop.ws_shutdown.shutdown(WS_SHUTDOWN_TIMEOUT).await,
+    }
+    stop.views.flush().context("persist view counts")
+}
+
+/// Still closes the sessions and persists the view counts when [`serve`] is
+/// dropped, which is how the Shuttle runtime stops a service.
+struct StopGuard {
+    ws_shutdown: WsShutdown,
+    views: Arc<ViewCounts>,
+}
+
+impl Drop for StopGuard {
+    fn drop(&mut self) {
+        self.ws_shutdown.cancel();
+        if let Err(e) = self.views.flush() {
+            tracing::error!("Failed to persist view counts: {:?}", e);
+        }
+    }
+}
+
+/// Resolves on Ctrl+C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("Shutting down");
+}
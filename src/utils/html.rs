@@ -0,0 +1,6 @@
+use std::borrow::Cow;
+
+/// Escape text to be embedded in HTML, including inside double-quoted attributes.
+pub fn escape_html(text: &str) -> Cow<'_, str> {
+    html_escape::encode_double_quoted_attribute(text)
+}
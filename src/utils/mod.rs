@@ -1,3 +1,5 @@
+mod html;
 mod rqwest;
 
+pub use html::*;
 pub use rqwest::*;
@@ -0,0 +1,183 @@
+use std::{fmt::Debug, future::Future, time::Duration};
+
+use axum::extract::ws::{close_code, CloseFrame, Message};
+use futures::{Sink, SinkExt};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+use crate::{config::Secrets, prelude::*};
+
+/// Heartbeat settings of the WebSocket sessions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsConfig {
+    /// How often the server pings the client.
+    pub ping_interval: Duration,
+    /// Sessions receiving nothing from the client for this long are closed.
+    pub idle_timeout: Duration,
+}
+
+impl Default for WsConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_secs(20),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl WsConfig {
+    /// Read from the `WS_PING_INTERVAL_SECS` and `WS_IDLE_TIMEOUT_SECS` secrets,
+    /// ignoring zero.
+    pub fn from_secrets(secrets: &Secrets) -> Self {
+        let default = Self::default();
+        Self {
+            ping_interval: non_zero_secs(secrets, "WS_PING_INTERVAL_SECS")
+                .unwrap_or(default.ping_interval),
+            idle_timeout: non_zero_secs(secrets, "WS_IDLE_TIMEOUT_SECS")
+                .unwrap_or(default.idle_timeout),
+        }
+    }
+
+    /// Ticks every `ping_interval`, starting one interval from now.
+    pub fn ping_timer(&self) -> tokio::time::Interval {
+        let mut timer = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.ping_interval,
+            self.ping_interval,
+        );
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        timer
+    }
+
+    pub fn idle_close(&self) -> CloseFrame<'static> {
+        CloseFrame {
+            code: close_code::NORMAL,
+            reason: f!("idle for more than {}s", self.idle_timeout.as_secs()).into(),
+        }
+    }
+}
+
+fn non_zero_secs(secrets: &Secrets, key: &str) -> Option<Duration> {
+    match secrets.parse(key)? {
+        0 => {
+            tracing::warn!("Ignoring {} 0: must be at least one second", key);
+            None
+        }
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// Send a close frame; failing only means the client is already gone.
+pub async fn send_close<S>(sender: &mut S, close: CloseFrame<'static>)
+where
+    S: Sink<Message> + Unpin,
+    S::Error: Debug,
+{
+    tracing::trace!("Closing websocket: {:?}", close);
+    if let Err(e) = sender.send(Message::Close(Some(close))).await {
+        tracing::debug!("Failed to send close frame: {:?}", e);
+    }
+}
+
+/// Tells the open WebSocket sessions to close when the server stops.
+#[derive(Debug, Clone, Default)]
+pub struct WsShutdown {
+    token: CancellationToken,
+    sessions: TaskTracker,
+}
+
+impl WsShutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Have [`shutdown`](Self::shutdown) wait for the session.
+    pub fn track<F: Future>(&self, session: F) -> impl Future<Output = F::Output> {
+        self.sessions.track_future(session)
+    }
+
+    /// Resolves once the sessions must close.
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+
+    /// Ask every session to close without waiting for them.
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    pub fn close_frame() -> CloseFrame<'static> {
+        CloseFrame {
+            code: close_code::AWAY,
+            reason: "server is shutting down".into(),
+        }
+    }
+
+    /// Ask every session to close, then wait up to `timeout` for them to do so.
+    pub async fn shutdown(&self, timeout: Duration) {
+        tracing::info!("Closing {} WebSocket sessions", self.sessions.len());
+        self.token.cancel();
+        self.sessions.close();
+        if tokio::time::timeout(timeout, self.sessions.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "{} WebSocket sessions still open after {:?}",
+                self.sessions.len(),
+                timeout
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_config_ignores_zero_durations() {
+        let secrets = |ping: &str, idle: &str| {
+            Secrets::new(
+                [
+                    ("WS_PING_INTERVAL_SECS".to_string(), ping.to_string()),
+                    ("WS_IDLE_TIMEOUT_SECS".to_string(), idle.to_string()),
+                ]
+                .into(),
+            )
+        };
+
+        assert_eq!(
+            WsConfig::from_secrets(&secrets("0", "0")),
+            WsConfig::default()
+        );
+        assert_eq!(
+            WsConfig::from_secrets(&secrets("5", "30")),
+            WsConfig {
+                ping_interval: Duration::from_secs(5),
+                idle_timeout: Duration::from_secs(30),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_sessions() {
+        let shutdown = WsShutdown::new();
+        let closed = Arc::new(AtomicBool::new(false));
+        let session = shutdown.track({
+            let (shutdown, closed) = (shutdown.clone(), closed.clone());
+            async move {
+                shutdown.cancelled().await;
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                closed.store(true, Ordering::Relaxed);
+            }
+        });
+        tokio::spawn(session);
+
+        shutdown.shutdown(Duration::from_secs(5)).await;
+        assert!(closed.load(Ordering::Relaxed));
+    }
+}